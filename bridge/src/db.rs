@@ -99,42 +99,6 @@ impl Database {
         Ok(())
     }
 
-    pub async fn get_registration(&self, app_id: &str) -> Result<Option<Registration>> {
-        let app_id = app_id.to_string();
-        let result = self
-            .conn
-            .call(move |conn| {
-                let mut stmt = conn.prepare(
-                    "SELECT app_id, endpoint, fcm_token, firebase_app_id, firebase_project_id, firebase_api_key, cert_sha1, app_version, app_version_name, target_sdk
-                     FROM registrations WHERE app_id = ?1",
-                )?;
-
-                let result = stmt.query_row([&app_id], |row| {
-                    Ok(Registration {
-                        app_id: row.get(0)?,
-                        endpoint: row.get(1)?,
-                        fcm_token: row.get(2)?,
-                        firebase_app_id: row.get(3)?,
-                        firebase_project_id: row.get(4)?,
-                        firebase_api_key: row.get(5)?,
-                        cert_sha1: row.get(6)?,
-                        app_version: row.get(7)?,
-                        app_version_name: row.get(8)?,
-                        target_sdk: row.get(9)?,
-                    })
-                });
-
-                match result {
-                    Ok(reg) => Ok(Some(reg)),
-                    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-                    Err(e) => Err(tokio_rusqlite::Error::Rusqlite(e)),
-                }
-            })
-            .await
-            .context("Failed to get registration")?;
-        Ok(result)
-    }
-
     pub async fn get_firebase_credentials(
         &self,
         app_id: &str,
@@ -163,22 +127,6 @@ impl Database {
         Ok(result)
     }
 
-    pub async fn update_endpoint(&self, app_id: &str, endpoint: &str) -> Result<()> {
-        let app_id = app_id.to_string();
-        let endpoint = endpoint.to_string();
-        self.conn
-            .call(move |conn| {
-                conn.execute(
-                    "UPDATE registrations SET endpoint = ?1, updated_at = CURRENT_TIMESTAMP WHERE app_id = ?2",
-                    params![endpoint, app_id],
-                )?;
-                Ok(())
-            })
-            .await
-            .context("Failed to update endpoint")?;
-        Ok(())
-    }
-
     pub async fn delete_registration(&self, app_id: &str) -> Result<()> {
         let app_id = app_id.to_string();
         self.conn
@@ -278,4 +226,16 @@ impl Database {
             .context("Failed to get FCM session")?;
         Ok(result)
     }
+
+    pub async fn delete_fcm_session(&self, app_id: &str) -> Result<()> {
+        let app_id = app_id.to_string();
+        self.conn
+            .call(move |conn| {
+                conn.execute("DELETE FROM fcm_sessions WHERE app_id = ?1", [&app_id])?;
+                Ok(())
+            })
+            .await
+            .context("Failed to delete FCM session")?;
+        Ok(())
+    }
 }
@@ -1,46 +1,121 @@
 //! FCM listener management
 //!
-//! Manages FCM connections for registered apps and forwards messages to UP endpoints.
+//! Registers apps on shared GCM devices, keeps one FCM connection per device and
+//! forwards each message to the UP endpoint of the app it was sent to.
 
 use crate::db::Database;
-use anyhow::Result;
-use fcm_listener::{FcmCredentials, Message, MessageStream, Registration};
+use anyhow::{Context, Result};
+use fcm_listener::{
+    ClientConfig, DataMessage, Event, FcmClient, FcmCredentials, FileIdStore, GcmDevice,
+    ListenOptions, Listener, PersistentIdStore, Registration,
+};
 use futures_util::StreamExt;
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use tokio::sync::mpsc;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
+
+/// Renew the Firebase Installations auth token when it expires within this window
+const TOKEN_RENEWAL_MARGIN: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Apps registered on one device before new apps get a device of their own
+///
+/// GCM answers TOO_MANY_REGISTRATIONS past some number of registrations per
+/// android_id, and one dropped connection should not silence every app.
+const MAX_APPS_PER_DEVICE: usize = 20;
 
 pub struct FcmManager {
-    /// Active listeners by app_id
-    listeners: HashMap<String, ListenerHandle>,
+    /// Devices by android_id, each with one FCM connection for all of its apps
+    devices: HashMap<i64, DeviceHandle>,
+    /// Apps whose messages are forwarded, by app_id
+    apps: Arc<RwLock<HashMap<String, AppRoute>>>,
     /// HTTP client for FCM registration
     http_client: reqwest::Client,
+    /// HTTP client for UnifiedPush endpoints, which do not go through the FCM proxy
+    up_client: reqwest::Client,
+    /// Endpoints and TLS settings for FCM
+    fcm_config: ClientConfig,
+    /// Directory holding the unconfirmed persistent IDs of each device
+    id_store_dir: PathBuf,
+    /// Listeners report here when they stop on their own
+    exit_tx: mpsc::UnboundedSender<ListenerExit>,
+    exit_rx: Option<mpsc::UnboundedReceiver<ListenerExit>>,
+}
+
+/// What deleting a saved registration needs, cloned out of [`FcmManager`] so its
+/// lock is not held across the calls to Google
+#[derive(Clone)]
+pub struct Unregisterer {
+    http_client: reqwest::Client,
+    fcm_config: ClientConfig,
 }
 
-struct ListenerHandle {
+/// Sent by a device listener that stopped without being asked to
+pub struct ListenerExit {
+    device: Arc<GcmDevice>,
+    /// The server refused the device's checkin, so the saved sessions of its apps are dead
+    login_rejected: bool,
+}
+
+struct DeviceHandle {
+    device: Arc<GcmDevice>,
     /// Channel to stop the listener
     stop_tx: mpsc::Sender<()>,
+}
+
+/// Where the messages of an app go and which registration they arrive for
+struct AppRoute {
+    /// UnifiedPush endpoint the messages are forwarded to
+    endpoint: String,
+    /// Device the app is registered on
+    android_id: i64,
+    sender_id: String,
     /// FCM token for this registration
     fcm_token: String,
 }
 
 impl FcmManager {
-    pub fn new() -> Self {
-        Self {
-            listeners: HashMap::new(),
-            http_client: reqwest::Client::builder()
-                .http1_only()
-                .build()
-                .expect("failed to build HTTP client"),
-        }
+    pub fn new(fcm_config: ClientConfig, id_store_dir: PathBuf) -> Result<Self> {
+        let http_client = fcm_config
+            .http_client()
+            .context("failed to build FCM HTTP client")?;
+        let up_client = reqwest::Client::builder()
+            .http1_only()
+            .build()
+            .context("failed to build UnifiedPush HTTP client")?;
+        let (exit_tx, exit_rx) = mpsc::unbounded_channel();
+        Ok(Self {
+            devices: HashMap::new(),
+            apps: Arc::new(RwLock::new(HashMap::new())),
+            http_client,
+            up_client,
+            fcm_config,
+            id_store_dir,
+            exit_tx,
+            exit_rx: Some(exit_rx),
+        })
     }
 
+    /// Receiver of listener exits, to be handed to [`reap_listeners`] once
+    pub fn take_listener_exits(&mut self) -> Option<mpsc::UnboundedReceiver<ListenerExit>> {
+        self.exit_rx.take()
+    }
+
+    /// Number of open FCM connections, one per device
     pub fn active_count(&self) -> usize {
-        self.listeners.len()
+        self.devices.len()
     }
 
+    /// Start forwarding FCM messages for an app, reusing its saved session if there is one
+    ///
+    /// With `reregister` set, a saved session Google no longer accepts is replaced
+    /// by a new registration; only do this when the returned token reaches the
+    /// app, i.e. on a request from the shim. Otherwise the saved session is
+    /// dropped and an error returned, so the next registration from the shim
+    /// starts over.
+    #[allow(clippy::too_many_arguments)]
     pub async fn start_listener(
         &mut self,
         app_id: String,
@@ -52,13 +127,9 @@ impl FcmManager {
         app_version_name: Option<String>,
         target_sdk: Option<i32>,
         endpoint: String,
+        reregister: bool,
         db: Arc<Database>,
     ) -> Result<String> {
-        // Stop existing listener if any
-        if let Some(handle) = self.listeners.remove(&app_id) {
-            let _ = handle.stop_tx.send(()).await;
-        }
-
         // Extract sender_id from firebase_app_id
         // Format: "1:<sender_id>:android:<hash>"
         let sender_id = extract_sender_id(&firebase_app_id)?;
@@ -77,77 +148,375 @@ impl FcmManager {
         };
 
         // Try to load existing session first
-        let registration = if let Ok(Some(session_json)) = db.get_fcm_session(&app_id).await {
-            match serde_json::from_str::<Registration>(&session_json) {
-                Ok(existing) => {
-                    info!(
-                        "Reusing existing FCM session for {} (token: {}...)",
-                        app_id,
-                        &existing.fcm_token()[..20.min(existing.fcm_token().len())]
-                    );
-                    existing
-                }
-                Err(e) => {
-                    warn!("Failed to deserialize saved session for {}: {}, re-registering", app_id, e);
-                    Registration::register(&self.http_client, &credentials).await?
-                }
+        let saved = match db.get_fcm_session(&app_id).await {
+            Ok(Some(session_json)) => Some(serde_json::from_str::<Registration>(&session_json)),
+            _ => None,
+        };
+        let (device, registration) = match saved {
+            Some(Ok(existing)) => {
+                self.restore_session(&app_id, existing, &credentials, reregister, &db)
+                    .await?
+            }
+            Some(Err(e)) if !reregister => {
+                drop_session(&db, &app_id).await;
+                anyhow::bail!(
+                    "saved FCM session for {} is unreadable: {}, the app has to register again",
+                    app_id,
+                    e
+                );
+            }
+            Some(Err(e)) => {
+                warn!("Failed to deserialize saved session for {}: {}, re-registering", app_id, e);
+                self.register_app(&credentials).await?
+            }
+            None => {
+                info!(
+                    "Registering with FCM for app: {} (sender_id: {}, cert: {})",
+                    app_id,
+                    sender_id,
+                    credentials.cert_sha1.as_deref().unwrap_or("none")
+                );
+                self.register_app(&credentials).await?
             }
-        } else {
-            info!(
-                "Registering with FCM for app: {} (sender_id: {}, cert: {})",
-                app_id,
-                sender_id,
-                credentials.cert_sha1.as_deref().unwrap_or("none")
-            );
-            Registration::register(&self.http_client, &credentials).await?
         };
 
         let fcm_token = registration.fcm_token().to_string();
         info!(
-            "Got FCM token for {}: {}...",
+            "Got FCM token for {}: {}... (android_id {})",
             app_id,
-            &fcm_token[..20.min(fcm_token.len())]
+            &fcm_token[..20.min(fcm_token.len())],
+            device.session().android_id
         );
 
         // Save registration for reconnection
-        if let Ok(reg_json) = serde_json::to_string(&registration) {
+        if let Ok(reg_json) = serde_json::to_string(&*registration) {
             let _ = db.save_fcm_session(&app_id, &reg_json).await;
         }
 
+        self.route(&app_id, device, &registration, endpoint);
+        Ok(fcm_token)
+    }
+
+    /// Put a saved registration back on its device, renewing its token if it is about to expire
+    async fn restore_session(
+        &self,
+        app_id: &str,
+        mut existing: Registration,
+        credentials: &FcmCredentials,
+        reregister: bool,
+        db: &Database,
+    ) -> Result<(Arc<GcmDevice>, Arc<Registration>)> {
+        info!(
+            "Reusing existing FCM session for {} (token: {}...)",
+            app_id,
+            &existing.fcm_token()[..20.min(existing.fcm_token().len())]
+        );
+        // Sessions saved without an installation have no FID to renew under,
+        // renewing would hand out a new FCM token, so they keep theirs
+        let needs_renewal = existing.firebase_installation.as_ref().is_some_and(|fis| {
+            fis.auth_token_expires_within(TOKEN_RENEWAL_MARGIN)
+        });
+        if needs_renewal {
+            // Renew under the same FID so the app server keeps its token
+            let old_token = existing.fcm_token().to_string();
+            let topics = existing.topics.clone();
+            match existing
+                .refresh_token(&self.http_client, &self.fcm_config)
+                .await
+            {
+                Err(e) if e.is_retryable() => {
+                    warn!("Failed to renew FCM token for {}: {}, keeping the current one", app_id, e);
+                }
+                Err(e) if !reregister => {
+                    // A new token would never reach the app server, let the shim
+                    // register the app again instead
+                    drop_session(db, app_id).await;
+                    anyhow::bail!(
+                        "saved FCM session for {} cannot be renewed: {}, the app has to register again",
+                        app_id,
+                        e
+                    );
+                }
+                Err(e) => {
+                    // Google no longer accepts this session, start over
+                    warn!("FCM token for {} cannot be renewed: {}, re-registering", app_id, e);
+                    if let Err(e) = existing
+                        .unregister(&self.http_client, &self.fcm_config)
+                        .await
+                    {
+                        debug!("Failed to delete old FCM token for {}: {}", app_id, e);
+                    }
+                    let (device, registration) = self.register_app(credentials).await?;
+                    let mut registration = (*registration).clone();
+                    self.resubscribe(app_id, &mut registration, topics).await;
+                    let registration = device.insert(registration)?;
+                    return Ok((device, registration));
+                }
+                Ok(()) => {}
+            }
+            if existing.fcm_token() != old_token {
+                if !reregister {
+                    warn!(
+                        "FCM token for {} changed on renewal, the app server gets it once the app registers again",
+                        app_id
+                    );
+                }
+                self.resubscribe(app_id, &mut existing, topics).await;
+            }
+        }
+
+        let android_id = existing.gcm_session.android_id;
+        let device = match self.devices.get(&android_id) {
+            Some(handle) => handle.device.clone(),
+            None => {
+                adopt_app_id_store(&self.id_store_dir, app_id, android_id);
+                Arc::new(GcmDevice::new(existing.gcm_session.clone()))
+            }
+        };
+        let registration = device.insert(existing)?;
+        Ok((device, registration))
+    }
+
+    /// Register a new app on the device with the most apps, checking in if all
+    /// devices are full or there is none
+    async fn register_app(
+        &self,
+        credentials: &FcmCredentials,
+    ) -> Result<(Arc<GcmDevice>, Arc<Registration>)> {
+        let busiest = self
+            .devices
+            .values()
+            .filter(|handle| handle.device.registrations().len() < MAX_APPS_PER_DEVICE)
+            .max_by_key(|handle| handle.device.registrations().len());
+        let device = match busiest {
+            Some(handle) => handle.device.clone(),
+            None => {
+                let device = GcmDevice::checkin(&self.http_client, &self.fcm_config).await?;
+                // Small delay between checkin and registration (microG has implicit delay)
+                tokio::time::sleep(self.fcm_config.checkin_delay).await;
+                Arc::new(device)
+            }
+        };
+        let registration = device
+            .register(&self.http_client, &self.fcm_config, credentials)
+            .await?;
+        Ok((device, registration))
+    }
+
+    /// Topic subscriptions belong to the old token, carry them over
+    async fn resubscribe(&self, app_id: &str, registration: &mut Registration, topics: Vec<String>) {
+        for topic in topics {
+            if let Err(e) = registration
+                .subscribe_topic(&self.http_client, &self.fcm_config, &topic)
+                .await
+            {
+                warn!("Failed to resubscribe {} to topic {}: {}", app_id, topic, e);
+            }
+        }
+    }
+
+    /// Forward the messages of `registration` to `endpoint`, connecting its device if needed
+    fn route(
+        &mut self,
+        app_id: &str,
+        device: Arc<GcmDevice>,
+        registration: &Registration,
+        endpoint: String,
+    ) {
+        let android_id = device.session().android_id;
+        let route = AppRoute {
+            endpoint,
+            android_id,
+            sender_id: registration.credentials.sender_id.clone(),
+            fcm_token: registration.fcm_token().to_string(),
+        };
+        let previous = self
+            .apps
+            .write()
+            .expect("lock poisoned")
+            .insert(app_id.to_string(), route);
+        if let Some(previous) = previous {
+            // The device already replaced a registration for the same sender
+            if previous.android_id != android_id
+                || previous.sender_id != registration.credentials.sender_id
+            {
+                self.detach(app_id, &previous);
+            }
+        }
+
+        if !self.devices.contains_key(&android_id) {
+            self.spawn_device(device);
+        }
+    }
+
+    /// Open the FCM connection of a device and forward what arrives on it
+    fn spawn_device(&mut self, device: Arc<GcmDevice>) {
+        let android_id = device.session().android_id;
+
+        // Keep unconfirmed persistent IDs on disk so a restart does not redeliver
+        let id_store: Option<Arc<dyn PersistentIdStore>> =
+            match FileIdStore::open(device_id_store_path(&self.id_store_dir, android_id)) {
+                Ok(store) => Some(Arc::new(store)),
+                Err(e) => {
+                    warn!("Failed to open persistent ID store for device {}: {}", android_id, e);
+                    None
+                }
+            };
+
+        // FcmClient reconnects with backoff, answers heartbeats and drops redelivered messages
+        let client = FcmClient::new(self.fcm_config.clone());
+        // Messages that sat in the queue past their TTL, e.g. while the bridge was down,
+        // are no longer worth a notification
+        let options = ListenOptions {
+            id_store,
+            drop_expired: true,
+            ..ListenOptions::default()
+        };
+        let listener = client.listen_device(device.clone(), options);
+
         // Create stop channel
         let (stop_tx, stop_rx) = mpsc::channel(1);
 
-        // Clone values for the listener task
-        let app_id_for_log = app_id.clone();
-        let fcm_token_clone = fcm_token.clone();
-        let http_client = self.http_client.clone();
-
         // Spawn listener task
-        tokio::spawn(async move {
-            run_listener(app_id_for_log, registration, endpoint, http_client, stop_rx).await;
-        });
+        tokio::spawn(run_listener(
+            android_id,
+            listener,
+            self.apps.clone(),
+            self.up_client.clone(),
+            stop_rx,
+            self.exit_tx.clone(),
+        ));
+
+        self.devices
+            .insert(android_id, DeviceHandle { device, stop_tx });
+    }
 
-        self.listeners.insert(
-            app_id,
-            ListenerHandle {
-                stop_tx,
-                fcm_token: fcm_token_clone,
-            },
-        );
+    /// Take an app off its device, closing the connection once no app is left on it
+    fn detach(&mut self, app_id: &str, route: &AppRoute) {
+        let Some(handle) = self.devices.get(&route.android_id) else {
+            return;
+        };
+        handle.device.remove(app_id, &route.sender_id);
+        if handle.device.registrations().is_empty() {
+            if let Some(handle) = self.devices.remove(&route.android_id) {
+                let _ = handle.stop_tx.try_send(());
+                info!("Stopped FCM listener for device {}", route.android_id);
+            }
+        }
+    }
 
-        Ok(fcm_token)
+    /// Forget a device whose listener stopped on its own, and stop forwarding for its apps
+    ///
+    /// After a login rejection the saved sessions of those apps are dropped, so the
+    /// next registration from the shim gives them a working token.
+    async fn listener_exited(&mut self, exit: ListenerExit, db: &Database) {
+        let android_id = exit.device.session().android_id;
+        match self.devices.get(&android_id) {
+            // A stale exit, the device was restarted since
+            Some(handle) if Arc::ptr_eq(&handle.device, &exit.device) => {}
+            _ => return,
+        }
+        self.devices.remove(&android_id);
+
+        let mut app_ids = Vec::new();
+        self.apps.write().expect("lock poisoned").retain(|app_id, route| {
+            if route.android_id == android_id {
+                app_ids.push(app_id.clone());
+            }
+            route.android_id != android_id
+        });
+
+        if exit.login_rejected {
+            for app_id in &app_ids {
+                drop_session(db, app_id).await;
+            }
+            warn!(
+                "Dropped the FCM sessions of device {}, these apps have to register again: {:?}",
+                android_id, app_ids
+            );
+        } else {
+            warn!("Stopped forwarding FCM messages for device {}: {:?}", android_id, app_ids);
+        }
     }
 
     pub fn stop_listener(&mut self, app_id: &str) {
-        if let Some(handle) = self.listeners.remove(app_id) {
-            let _ = handle.stop_tx.try_send(());
-            info!("Stopped FCM listener for {}", app_id);
+        let route = self.apps.write().expect("lock poisoned").remove(app_id);
+        if let Some(route) = route {
+            self.detach(app_id, &route);
+            info!("Stopped forwarding FCM messages for {}", app_id);
+        }
+    }
+
+    pub fn unregisterer(&self) -> Unregisterer {
+        Unregisterer {
+            http_client: self.http_client.clone(),
+            fcm_config: self.fcm_config.clone(),
         }
     }
 
     #[allow(dead_code)]
-    pub fn get_fcm_token(&self, app_id: &str) -> Option<&str> {
-        self.listeners.get(app_id).map(|h| h.fcm_token.as_str())
+    pub fn get_fcm_token(&self, app_id: &str) -> Option<String> {
+        self.apps
+            .read()
+            .expect("lock poisoned")
+            .get(app_id)
+            .map(|route| route.fcm_token.clone())
+    }
+}
+
+impl Unregisterer {
+    /// Delete a saved FCM registration on Google's side
+    pub async fn unregister(&self, app_id: &str, session_json: &str) -> Result<()> {
+        let mut registration = serde_json::from_str::<Registration>(session_json)?;
+        registration
+            .unregister(&self.http_client, &self.fcm_config)
+            .await?;
+        info!("Deleted FCM token for {}", app_id);
+        Ok(())
+    }
+}
+
+/// File of the persistent ID store for a device
+fn device_id_store_path(id_store_dir: &Path, android_id: i64) -> PathBuf {
+    id_store_dir.join(format!("device-{android_id}.ids"))
+}
+
+/// Take over the persistent ID store an app kept when it had a connection of its own
+///
+/// Sessions saved before apps shared devices each have their own android_id, so
+/// the app's IDs are exactly the ones its device has not confirmed.
+fn adopt_app_id_store(id_store_dir: &Path, app_id: &str, android_id: i64) {
+    let name: String = app_id
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    let app_path = id_store_dir.join(format!("{name}.ids"));
+    let device_path = device_id_store_path(id_store_dir, android_id);
+    if app_path.exists() && !device_path.exists() {
+        if let Err(e) = std::fs::rename(&app_path, &device_path) {
+            warn!("Failed to move persistent ID store of {} to device {}: {}", app_id, android_id, e);
+        }
+    }
+}
+
+/// Clean up after device listeners that stopped on their own
+pub async fn reap_listeners(
+    manager: Arc<tokio::sync::RwLock<FcmManager>>,
+    mut exits: mpsc::UnboundedReceiver<ListenerExit>,
+    db: Arc<Database>,
+) {
+    while let Some(exit) = exits.recv().await {
+        manager.write().await.listener_exited(exit, &db).await;
+    }
+}
+
+/// Forget an app's saved FCM session, so its next registration starts from scratch
+async fn drop_session(db: &Database, app_id: &str) {
+    if let Err(e) = db.delete_fcm_session(app_id).await {
+        error!("Failed to delete saved FCM session for {}: {}", app_id, e);
     }
 }
 
@@ -163,150 +532,169 @@ fn extract_sender_id(firebase_app_id: &str) -> Result<String> {
 }
 
 async fn run_listener(
-    app_id: String,
-    registration: Registration,
-    endpoint: String,
-    http_client: reqwest::Client,
+    android_id: i64,
+    mut listener: Listener,
+    apps: Arc<RwLock<HashMap<String, AppRoute>>>,
+    up_client: reqwest::Client,
     mut stop_rx: mpsc::Receiver<()>,
+    exit_tx: mpsc::UnboundedSender<ListenerExit>,
 ) {
-    info!("Starting FCM listener for {}", app_id);
-
-    // Track persistent IDs to avoid duplicate messages
-    let mut persistent_ids: Vec<String> = Vec::new();
-
-    loop {
-        // Check if we should stop
-        if stop_rx.try_recv().is_ok() {
-            info!("FCM listener stopped for {}", app_id);
-            break;
-        }
-
-        // Connect to mtalk.google.com
-        let connection = match registration
-            .gcm_session
-            .connect(persistent_ids.clone())
-            .await
-        {
-            Ok(conn) => conn,
-            Err(e) => {
-                error!("FCM connection failed for {}: {}", app_id, e);
-                tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
-                continue;
+    info!("Starting FCM listener for device {}", android_id);
+
+    let login_rejected = loop {
+        tokio::select! {
+            _ = stop_rx.recv() => {
+                listener.close();
+                info!("FCM listener stopped for device {}", android_id);
+                return;
             }
-        };
 
-        info!("FCM connection established for {}", app_id);
+            event = listener.next() => {
+                match event {
+                    Some(Event::Connecting { attempt }) => {
+                        debug!("Connecting to FCM for device {} (attempt {})", android_id, attempt);
+                    }
 
-        // Wrap connection in MessageStream (no encryption keys needed for Android FCM)
-        let mut stream = MessageStream::new(connection.0);
+                    Some(Event::Connected { endpoint }) => {
+                        info!("FCM connection established for device {} via {}", android_id, endpoint);
+                    }
 
-        // Listen for messages
-        loop {
-            tokio::select! {
-                _ = stop_rx.recv() => {
-                    info!("FCM listener stopped for {}", app_id);
-                    return;
-                }
+                    Some(Event::Disconnected { error, retry_in }) => match error {
+                        Some(fcm_listener::Error::HeartbeatTimeout) => warn!(
+                            "FCM connection for device {} stopped answering heartbeats, reconnecting in {:?}",
+                            android_id, retry_in
+                        ),
+                        Some(e) => warn!(
+                            "FCM connection lost for device {}: {}, reconnecting in {:?}",
+                            android_id, e, retry_in
+                        ),
+                        None => warn!(
+                            "FCM stream ended for device {}, reconnecting in {:?}",
+                            android_id, retry_in
+                        ),
+                    },
+
+                    Some(Event::Message { message, registration: None, .. }) => {
+                        warn!(
+                            "Dropping FCM message for device {} that matches no app: persistent_id: {:?}, category: {:?}, from: {:?}",
+                            android_id, message.persistent_id, message.category, message.from
+                        );
+                    }
 
-                msg = stream.next() => {
-                    match msg {
-                        Some(Ok(Message::Data(data))) => {
-                            let payload_len = data.raw_data.as_ref().map(|d| d.len()).unwrap_or(0);
-                            info!(
-                                "Received FCM message for {}: {} bytes, persistent_id: {:?}, from: {:?}",
-                                app_id,
-                                payload_len,
-                                data.persistent_id,
-                                data.from
-                            );
-
-                            // Track persistent ID
-                            if let Some(pid) = &data.persistent_id {
-                                if !persistent_ids.contains(pid) {
-                                    persistent_ids.push(pid.clone());
-                                    // Keep only last 100 IDs
-                                    if persistent_ids.len() > 100 {
-                                        persistent_ids.remove(0);
-                                    }
-                                }
+                    Some(Event::Message { message, decrypted, registration: Some(registration) }) => {
+                        let app_id = &registration.credentials.package_name;
+                        let payload_len = message.raw_data.as_ref().map(|d| d.len()).unwrap_or(0);
+                        info!(
+                            "Received FCM message for {}: {} bytes, persistent_id: {:?}, message_id: {:?}, from: {:?}, queued: {:?}",
+                            app_id,
+                            payload_len,
+                            message.persistent_id,
+                            message.fcm_payload().message_id,
+                            message.from,
+                            message.queued_duration()
+                        );
+
+                        // Forward to UnifiedPush endpoint
+                        // Encrypted payloads were decrypted with the session keys, plaintext
+                        // ones go out as raw data or as app_data JSON
+                        let body = match decrypted {
+                            Some(decrypted) => {
+                                info!("Decrypted FCM message for {}: {} bytes", app_id, decrypted.len());
+                                decrypted
                             }
+                            None => match message.raw_data {
+                                Some(raw) => raw,
+                                None => app_data_json(&message),
+                            },
+                        };
+                        forward(&apps, app_id, &body, &up_client).await;
+                    }
 
-                            // Forward to UnifiedPush endpoint
-                            // For Android FCM, the payload might be in raw_data or app_data
-                            let body = if let Some(raw) = &data.raw_data {
-                                raw.clone()
-                            } else {
-                                // Check for encrypted payload in app_data
-                                let app_data_map: HashMap<&str, &str> = data
-                                    .app_data
-                                    .iter()
-                                    .map(|(k, v)| (k.as_str(), v.as_str()))
-                                    .collect();
-
-                                if let Some(encrypted) = app_data_map.get("encrypted") {
-                                    // Decrypt the payload using session keys
-                                    match registration.gcm_session.decrypt(encrypted) {
-                                        Ok(decrypted) => {
-                                            info!("Decrypted FCM message for {}: {} bytes", app_id, decrypted.len());
-                                            decrypted
-                                        }
-                                        Err(e) => {
-                                            warn!("Failed to decrypt FCM message for {}: {}, forwarding raw", app_id, e);
-                                            serde_json::to_vec(&app_data_map).unwrap_or_default()
-                                        }
-                                    }
-                                } else {
-                                    // No encryption, serialize app_data as JSON
-                                    serde_json::to_vec(&app_data_map).unwrap_or_default()
-                                }
-                            };
-
-                            if !body.is_empty() {
-                                if let Err(e) = forward_to_up(&endpoint, &body, &http_client).await {
-                                    error!("Failed to forward to UP for {}: {}", app_id, e);
-                                } else {
-                                    info!("Forwarded message to UP endpoint for {}", app_id);
-                                }
-                            } else {
-                                warn!("Empty payload in FCM message for {}", app_id);
-                            }
-                        }
-
-                        Some(Ok(Message::HeartbeatPing)) => {
-                            // Send heartbeat ack
-                            let ack = fcm_listener::new_heartbeat_ack();
-                            if let Err(e) = stream.write_all(&ack).await {
-                                error!("Failed to send heartbeat ack for {}: {}", app_id, e);
-                                break; // Reconnect
-                            }
-                        }
+                    Some(Event::DecryptionFailed { message, error, registration }) => {
+                        let app_id = &registration.credentials.package_name;
+                        warn!("Failed to decrypt FCM message for {}: {}, forwarding raw", app_id, error);
+                        forward(&apps, app_id, &app_data_json(&message), &up_client).await;
+                    }
 
-                        Some(Ok(Message::Other(tag, _))) => {
-                            warn!("Unknown FCM message type {} for {}", tag, app_id);
-                        }
+                    Some(Event::Failed(fcm_listener::Error::LoginRejected(e))) => {
+                        error!(
+                            "FCM login rejected for device {}: {} (saved sessions of its apps are no longer valid)",
+                            android_id, e
+                        );
+                        break true;
+                    }
 
-                        Some(Err(e)) => {
-                            error!("FCM receive error for {}: {}", app_id, e);
-                            break; // Reconnect
-                        }
+                    Some(Event::Failed(e)) => {
+                        error!("FCM listener for device {} gave up: {}", android_id, e);
+                        break false;
+                    }
 
-                        None => {
-                            warn!("FCM stream ended for {}", app_id);
-                            break; // Reconnect
-                        }
+                    None => {
+                        warn!("FCM listener for device {} ended", android_id);
+                        break false;
                     }
                 }
             }
         }
+    };
 
-        // Wait before reconnecting
-        warn!("FCM connection lost for {}, reconnecting in 5s...", app_id);
-        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+    let _ = exit_tx.send(ListenerExit {
+        device: listener.device().clone(),
+        login_rejected,
+    });
+}
+
+/// Serialize a message's app_data as a JSON object
+///
+/// The notification title and body are repeated as `notification_title` and
+/// `notification_body` for the shim's notification fallback, unless the app
+/// data already uses those keys.
+fn app_data_json(message: &DataMessage) -> Vec<u8> {
+    let mut app_data_map: HashMap<&str, &str> = message
+        .app_data
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+
+    let payload = message.fcm_payload();
+    if let Some(notification) = &payload.notification {
+        if let Some(title) = &notification.title {
+            app_data_map.entry("notification_title").or_insert(title);
+        }
+        if let Some(body) = &notification.body {
+            app_data_map.entry("notification_body").or_insert(body);
+        }
     }
+    serde_json::to_vec(&app_data_map).unwrap_or_default()
 }
 
-async fn forward_to_up(endpoint: &str, body: &[u8], http_client: &reqwest::Client) -> Result<()> {
-    let response = http_client
+async fn forward(
+    apps: &RwLock<HashMap<String, AppRoute>>,
+    app_id: &str,
+    body: &[u8],
+    up_client: &reqwest::Client,
+) {
+    let endpoint = apps
+        .read()
+        .expect("lock poisoned")
+        .get(app_id)
+        .map(|route| route.endpoint.clone());
+    let Some(endpoint) = endpoint else {
+        warn!("Dropping FCM message for {}: the app is no longer registered", app_id);
+        return;
+    };
+
+    if body.is_empty() {
+        warn!("Empty payload in FCM message for {}", app_id);
+    } else if let Err(e) = forward_to_up(&endpoint, body, up_client).await {
+        error!("Failed to forward to UP for {}: {}", app_id, e);
+    } else {
+        info!("Forwarded message to UP endpoint for {}", app_id);
+    }
+}
+
+async fn forward_to_up(endpoint: &str, body: &[u8], up_client: &reqwest::Client) -> Result<()> {
+    let response = up_client
         .post(endpoint)
         .header("Content-Type", "application/octet-stream")
         .body(body.to_vec())
@@ -319,9 +707,3 @@ async fn forward_to_up(endpoint: &str, body: &[u8], http_client: &reqwest::Clien
 
     Ok(())
 }
-
-impl Default for FcmManager {
-    fn default() -> Self {
-        Self::new()
-    }
-}
@@ -0,0 +1,362 @@
+use crate::push::MessageStream;
+use crate::{
+    ClientConfig, DataMessage, Error, GcmDevice, HeartbeatOptions, MemoryIdStore, Message,
+    MtalkEndpoint, PersistentIdStore, Registration,
+};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::StreamExt;
+
+/// Connections that stayed up this long reset the reconnect backoff
+const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+/// High-level FCM client that keeps registrations connected
+///
+/// ```rust,no_run
+/// # async fn run(registration: fcm_listener::Registration) {
+/// use fcm_listener::{ClientConfig, Event, FcmClient, ListenOptions};
+/// use tokio_stream::StreamExt;
+///
+/// let client = FcmClient::new(ClientConfig::default());
+/// let mut listener = client.listen(registration, ListenOptions::default());
+/// while let Some(event) = listener.next().await {
+///     if let Event::Message { message, decrypted, .. } = event {
+///         println!("{:?}: {:?}", message.persistent_id, decrypted);
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct FcmClient {
+    config: ClientConfig,
+}
+
+/// Settings for [`FcmClient::listen`]
+#[derive(Clone, Debug)]
+pub struct ListenOptions {
+    /// Client heartbeat settings for every connection
+    pub heartbeat: HeartbeatOptions,
+    /// Delay before the first reconnect, doubled after each failed attempt
+    pub initial_backoff: Duration,
+    /// Upper bound for the reconnect delay
+    pub max_backoff: Duration,
+    /// Whether encrypted messages are decrypted with the session keys
+    pub decrypt: bool,
+    /// Whether messages that outlived their TTL while queued are dropped
+    pub drop_expired: bool,
+    /// Where unconfirmed persistent IDs are kept, in memory if `None`
+    ///
+    /// Use a [`crate::FileIdStore`] to avoid redeliveries after a restart.
+    pub id_store: Option<Arc<dyn PersistentIdStore>>,
+}
+
+impl Default for ListenOptions {
+    fn default() -> Self {
+        Self {
+            heartbeat: HeartbeatOptions::default(),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5 * 60),
+            decrypt: true,
+            drop_expired: false,
+            id_store: None,
+        }
+    }
+}
+
+/// Item of a [`Listener`]
+pub enum Event {
+    /// Opening connection attempt `attempt`
+    ///
+    /// Counts from 1 again after a connection that stayed up for a minute, so
+    /// connections the server drops right after the login keep backing off.
+    Connecting { attempt: u32 },
+    /// Logged in to the MCS server at `endpoint`, messages are flowing
+    Connected { endpoint: MtalkEndpoint },
+    /// Connection dropped or could not be opened, reconnecting after `retry_in`
+    ///
+    /// `error` is `None` when the server closed the stream cleanly, a stream
+    /// error it sent arrives as [`Error::StreamError`].
+    Disconnected {
+        error: Option<Error>,
+        retry_in: Duration,
+    },
+    /// New data message, `decrypted` holds the plaintext of an encrypted one
+    ///
+    /// `registration` is the app the message was routed to, `None` if no
+    /// registration of the device matched. Encrypted messages are only
+    /// decrypted once routed.
+    Message {
+        message: DataMessage,
+        decrypted: Option<Vec<u8>>,
+        registration: Option<Arc<Registration>>,
+    },
+    /// Encrypted message that the keys of its registration could not decrypt
+    DecryptionFailed {
+        message: DataMessage,
+        error: Error,
+        registration: Arc<Registration>,
+    },
+    /// The server refused the session, the listener has stopped
+    ///
+    /// Usually [`Error::LoginRejected`], the registration has to be replaced.
+    Failed(Error),
+}
+
+/// Stream of [`Event`]s from [`FcmClient::listen`] or [`FcmClient::listen_device`]
+///
+/// Dropping the listener or calling [`Listener::close`] closes the connection.
+pub struct Listener {
+    events: mpsc::Receiver<Event>,
+    stop: Option<oneshot::Sender<()>>,
+    device: Arc<GcmDevice>,
+}
+
+impl FcmClient {
+    pub fn new(config: ClientConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    /// Keep `registration` connected to MCS and stream what arrives
+    ///
+    /// The listener reconnects with exponential backoff until it is closed or
+    /// the server rejects the login. Messages the server redelivers after a
+    /// reconnect are dropped by persistent ID. Must be called within a Tokio
+    /// runtime.
+    pub fn listen(&self, registration: Registration, options: ListenOptions) -> Listener {
+        self.listen_device(
+            Arc::new(GcmDevice::from_registration(registration)),
+            options,
+        )
+    }
+
+    /// Keep one connection open for every registration of `device`
+    ///
+    /// Messages are routed with [`GcmDevice::route`] and decrypted with the keys
+    /// of the matching registration. Registrations added to or removed from the
+    /// device take effect for the next message, no reconnect is needed.
+    pub fn listen_device(&self, device: Arc<GcmDevice>, options: ListenOptions) -> Listener {
+        let (events_tx, events) = mpsc::channel(64);
+        let (stop, stop_rx) = oneshot::channel();
+        let id_store = options
+            .id_store
+            .clone()
+            .unwrap_or_else(|| Arc::new(MemoryIdStore::new()));
+
+        let task = ListenTask {
+            config: self.config.clone(),
+            device: device.clone(),
+            options,
+            events: events_tx,
+            id_store,
+        };
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = stop_rx => {}
+                _ = task.run() => {}
+            }
+        });
+
+        Listener {
+            events,
+            stop: Some(stop),
+            device,
+        }
+    }
+}
+
+impl Listener {
+    /// The device this listener is connected as
+    pub fn device(&self) -> &Arc<GcmDevice> {
+        &self.device
+    }
+
+    /// Stop listening and close the connection
+    ///
+    /// Events already received can still be read, then the stream ends.
+    pub fn close(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        self.events.close();
+    }
+}
+
+impl tokio_stream::Stream for Listener {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+struct ListenTask {
+    config: ClientConfig,
+    device: Arc<GcmDevice>,
+    options: ListenOptions,
+    events: mpsc::Sender<Event>,
+    /// Received IDs the server has not confirmed, resent on the next login
+    id_store: Arc<dyn PersistentIdStore>,
+}
+
+impl ListenTask {
+    async fn run(mut self) {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            if !self.emit(Event::Connecting { attempt }).await {
+                return;
+            }
+
+            let persistent_ids = self.id_store.load().unwrap_or_else(|e| {
+                tracing::warn!("Failed to load persistent IDs: {}", e);
+                Vec::new()
+            });
+            let error = match self
+                .device
+                .connect(&self.config, persistent_ids.clone())
+                .await
+            {
+                Ok(connection) => {
+                    // IDs sent in the login are acknowledged by it
+                    if let Err(e) = self.id_store.remove(&persistent_ids) {
+                        tracing::warn!("Failed to forget persistent IDs: {}", e);
+                    }
+                    self.flush_ids().await;
+                    let endpoint = connection.endpoint.clone();
+                    if !self.emit(Event::Connected { endpoint }).await {
+                        return;
+                    }
+                    let started = tokio::time::Instant::now();
+                    let stream = MessageStream::from_connection(connection, self.options.heartbeat)
+                        .with_id_store(self.id_store.clone())
+                        .with_drop_expired(self.options.drop_expired);
+                    let received = self.receive(stream).await;
+                    self.flush_ids().await;
+                    let Some(error) = received else {
+                        return;
+                    };
+                    if started.elapsed() >= STABLE_CONNECTION {
+                        attempt = 0;
+                    }
+                    error
+                }
+                Err(e @ Error::LoginRejected(_)) => {
+                    tracing::error!("FCM login rejected: {}", e);
+                    self.emit(Event::Failed(e)).await;
+                    return;
+                }
+                Err(e) => Some(e),
+            };
+
+            let retry_in = self.backoff(attempt);
+            if let Some(e) = &error {
+                tracing::warn!("FCM connection failed: {}, reconnecting in {:?}", e, retry_in);
+            }
+            if !self.emit(Event::Disconnected { error, retry_in }).await {
+                return;
+            }
+            tokio::time::sleep(retry_in).await;
+        }
+    }
+
+    /// Forward messages until the connection ends
+    ///
+    /// Returns `None` once the listener has been dropped, otherwise the error
+    /// that ended the connection, if any.
+    async fn receive<T>(&mut self, mut stream: MessageStream<T>) -> Option<Option<Error>>
+    where
+        T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        loop {
+            let event = match stream.next().await {
+                Some(Ok(Message::Data(message))) => {
+                    // the stream recorded the ID and dropped redeliveries, make the
+                    // ID durable before handing the message out
+                    self.flush_ids().await;
+                    self.decrypt(message)
+                }
+                Some(Ok(Message::Close)) => return Some(None),
+                Some(Ok(Message::StreamError(stream_error))) => {
+                    return Some(Some(Error::StreamError {
+                        r#type: stream_error.r#type,
+                        text: stream_error.text,
+                    }));
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Some(Some(e)),
+                None => return Some(None),
+            };
+
+            if !self.emit(event).await {
+                return None;
+            }
+        }
+    }
+
+    fn decrypt(&self, message: DataMessage) -> Event {
+        let Some(registration) = self.device.route(&message) else {
+            tracing::warn!(
+                "No registration for FCM message {:?} to {:?}",
+                message.persistent_id,
+                message.category
+            );
+            return Event::Message {
+                message,
+                decrypted: None,
+                registration: None,
+            };
+        };
+        if !self.options.decrypt || message.content_encoding().is_none() {
+            return Event::Message {
+                message,
+                decrypted: None,
+                registration: Some(registration),
+            };
+        }
+        match registration.gcm_session.decrypt_message(&message) {
+            Ok(decrypted) => Event::Message {
+                message,
+                decrypted: Some(decrypted),
+                registration: Some(registration),
+            },
+            Err(error) => Event::DecryptionFailed {
+                message,
+                error,
+                registration,
+            },
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(16) as i32;
+        let delay = self
+            .options
+            .initial_backoff
+            .mul_f64(2f64.powi(exponent))
+            .min(self.options.max_backoff);
+        // Spread reconnects of many listeners by up to 20% either way
+        delay.mul_f64(0.8 + rand::random::<f64>() * 0.4)
+    }
+
+    /// Write out the persistent ID store without blocking the runtime
+    async fn flush_ids(&self) {
+        let store = self.id_store.clone();
+        match tokio::task::spawn_blocking(move || store.flush()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::warn!("Failed to write persistent IDs: {}", e),
+            Err(e) => tracing::warn!("Persistent ID flush panicked: {}", e),
+        }
+    }
+
+    /// Send an event, `false` once the listener has been dropped
+    async fn emit(&self, event: Event) -> bool {
+        self.events.send(event).await.is_ok()
+    }
+}
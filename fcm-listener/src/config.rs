@@ -0,0 +1,170 @@
+use crate::{DeviceProfile, Error, Proxy, RetryPolicy};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+
+/// Addresses of the Google services used for registration and listening
+#[derive(Clone, Debug)]
+pub struct Endpoints {
+    /// Android device checkin, e.g., "https://android.clients.google.com/checkin"
+    pub checkin_url: String,
+    /// GCM registration, e.g., "https://android.clients.google.com/c2dm/register3"
+    pub register_url: String,
+    /// Firebase Installations API base URL, without the `/projects/...` path
+    pub installations_url: String,
+    /// MCS host, also the name its TLS certificate is verified against
+    pub mtalk_host: String,
+    /// MCS port
+    pub mtalk_port: u16,
+    /// MCS servers tried in order when `mtalk_host:mtalk_port` cannot be reached,
+    /// by default ports 443 and 5229 and the alternate mtalk hosts for networks
+    /// that block 5228
+    pub mtalk_fallbacks: Vec<MtalkEndpoint>,
+}
+
+/// Host and port of an MCS server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MtalkEndpoint {
+    pub host: String,
+    pub port: u16,
+}
+
+impl MtalkEndpoint {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+        }
+    }
+}
+
+impl std::fmt::Display for MtalkEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self {
+            checkin_url: "https://android.clients.google.com/checkin".into(),
+            // microG uses android.clients.google.com
+            register_url: "https://android.clients.google.com/c2dm/register3".into(),
+            installations_url: "https://firebaseinstallations.googleapis.com/v1".into(),
+            mtalk_host: "mtalk.google.com".into(),
+            mtalk_port: 5228,
+            // 443 gets through most firewalls, mtalk4 is IPv4 only and the alt
+            // hosts are the ones Android devices fall back to
+            mtalk_fallbacks: vec![
+                MtalkEndpoint::new("mtalk.google.com", 443),
+                MtalkEndpoint::new("mtalk.google.com", 5229),
+                MtalkEndpoint::new("mtalk4.google.com", 5228),
+                MtalkEndpoint::new("mtalk4.google.com", 443),
+                MtalkEndpoint::new("alt1-mtalk.google.com", 5228),
+                MtalkEndpoint::new("alt1-mtalk.google.com", 443),
+            ],
+        }
+    }
+}
+
+impl Endpoints {
+    /// MCS servers in the order they are tried, the primary one first
+    pub fn mtalk_candidates(&self) -> Vec<MtalkEndpoint> {
+        let primary = MtalkEndpoint::new(self.mtalk_host.clone(), self.mtalk_port);
+        let mut candidates = vec![primary];
+        for fallback in &self.mtalk_fallbacks {
+            if !candidates.contains(fallback) {
+                candidates.push(fallback.clone());
+            }
+        }
+        candidates
+    }
+}
+
+/// Settings shared by registration and the MCS connection
+///
+/// The default talks to Google's production hosts and trusts the webpki roots.
+#[derive(Clone)]
+pub struct ClientConfig {
+    /// Where to reach checkin, register, Firebase Installations and mtalk
+    pub endpoints: Endpoints,
+    /// TLS configuration for the MCS connection, replaces the webpki roots entirely
+    pub tls: Option<Arc<rustls::ClientConfig>>,
+    /// Root certificates trusted in addition to the webpki roots
+    pub extra_root_certificates: Vec<CertificateDer<'static>>,
+    /// Device to check in as, a Pixel 5 with a random serial number when unset
+    ///
+    /// Only used for new checkins, existing sessions keep their own profile.
+    pub device: Option<DeviceProfile>,
+    /// Retries for checkin, Firebase Installations and GCM register calls
+    pub retry: RetryPolicy,
+    /// Pause between the checkin and the first registration call of
+    /// [`Registration::register`](crate::Registration::register)
+    pub checkin_delay: Duration,
+    /// Proxy for the MCS connection and for clients built by [`ClientConfig::http_client`]
+    pub proxy: Option<Proxy>,
+    /// Time allowed for connecting and logging in to one MCS server before
+    /// the next candidate is tried
+    pub mtalk_attempt_timeout: Duration,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: Endpoints::default(),
+            tls: None,
+            extra_root_certificates: Vec::new(),
+            device: None,
+            retry: RetryPolicy::default(),
+            // microG has an implicit delay here
+            checkin_delay: Duration::from_millis(500),
+            proxy: None,
+            mtalk_attempt_timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Build an HTTP client for checkin and registration that trusts the same roots
+    /// and uses the same proxy as the MCS connection
+    pub fn http_client(&self) -> Result<reqwest::Client, Error> {
+        let mut builder = reqwest::Client::builder().http1_only();
+
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.to_reqwest()?);
+        }
+
+        if let Some(tls) = &self.tls {
+            builder = builder.use_preconfigured_tls((**tls).clone());
+        }
+
+        for certificate in &self.extra_root_certificates {
+            let certificate = reqwest::Certificate::from_der(certificate)
+                .map_err(|e| Error::Request("HTTP client", e))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        builder.build().map_err(|e| Error::Request("HTTP client", e))
+    }
+
+    pub(crate) fn tls_connector(&self) -> tokio_rustls::TlsConnector {
+        if let Some(tls) = &self.tls {
+            return tokio_rustls::TlsConnector::from(tls.clone());
+        }
+
+        let mut root_store = tokio_rustls::rustls::RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        for certificate in &self.extra_root_certificates {
+            if let Err(e) = root_store.add(certificate.clone()) {
+                tracing::warn!("Ignoring invalid extra root certificate: {}", e);
+            }
+        }
+
+        let config = tokio_rustls::rustls::ClientConfig::builder()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+
+        tokio_rustls::TlsConnector::from(Arc::new(config))
+    }
+}
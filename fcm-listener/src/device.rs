@@ -0,0 +1,157 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Android device identity presented to GCM during checkin and registration
+///
+/// A session keeps the profile it checked in with, so re-checkins report the same
+/// device. Profiles can be loaded with serde; a missing serial number or MAC address
+/// is generated randomly.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeviceProfile {
+    /// Build fingerprint, e.g., "google/redfin/redfin:14/AP2A.240805.005/12025142:user/release-keys"
+    pub fingerprint: String,
+    /// Build ID, e.g., "AP2A.240805.005", also used in user agents
+    pub build_id: String,
+    /// Build time in seconds since the Unix epoch
+    pub build_time: i64,
+    /// Android SDK level, e.g., 34
+    pub sdk_version: i32,
+    pub hardware: String,
+    pub brand: String,
+    pub device: String,
+    pub product: String,
+    pub model: String,
+    pub manufacturer: String,
+    pub radio: String,
+    pub bootloader: String,
+    #[serde(default = "random_serial_number")]
+    pub serial_number: String,
+    /// Wi-Fi MAC address (lowercase hex, no separators)
+    #[serde(default = "random_mac_address")]
+    pub mac_address: String,
+    /// Locale in xx_XX format
+    #[serde(default = "default_locale")]
+    pub locale: String,
+    /// IANA time zone name
+    #[serde(default = "default_time_zone")]
+    pub time_zone: String,
+}
+
+impl DeviceProfile {
+    /// Google Pixel 5 ("redfin") on Android 14
+    pub fn pixel_5() -> Self {
+        Self::google(
+            "redfin",
+            "Pixel 5",
+            "g7250-00217-231219-B-11446880",
+            "slider-1.2-10323765",
+        )
+    }
+
+    /// Google Pixel 7 ("panther") on Android 14
+    pub fn pixel_7() -> Self {
+        Self::google(
+            "panther",
+            "Pixel 7",
+            "g5300q-230626-240409-B-11728843",
+            "cloudripper-14.5-11720913",
+        )
+    }
+
+    /// Google Pixel 8 ("shiba") on Android 14
+    pub fn pixel_8() -> Self {
+        Self::google(
+            "shiba",
+            "Pixel 8",
+            "g5300i-240308-240415-B-11760214",
+            "ripcurrent-14.5-11716581",
+        )
+    }
+
+    /// Look up a built-in preset by name ("pixel_5", "pixel_7" or "pixel_8")
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "pixel_5" => Some(Self::pixel_5()),
+            "pixel_7" => Some(Self::pixel_7()),
+            "pixel_8" => Some(Self::pixel_8()),
+            _ => None,
+        }
+    }
+
+    /// The fixed Pixel 5 identity used before profiles were configurable
+    ///
+    /// Sessions saved without a profile keep checking in as this device.
+    pub fn legacy() -> Self {
+        Self {
+            serial_number: "RF8M33YQXMR".into(),
+            mac_address: "aabbccddeeff".into(),
+            ..Self::pixel_5()
+        }
+    }
+
+    /// User-Agent for the checkin request
+    pub(crate) fn checkin_user_agent(&self) -> String {
+        format!("Android-Checkin/2.0 ({} {}); gzip", self.device, self.build_id)
+    }
+
+    /// User-Agent for GCM registration requests
+    pub(crate) fn gcm_user_agent(&self) -> String {
+        format!("Android-GCM/1.5 ({} {})", self.device, self.build_id)
+    }
+
+    fn google(codename: &str, model: &str, radio: &str, bootloader: &str) -> Self {
+        const BUILD_ID: &str = "AP2A.240805.005";
+
+        Self {
+            fingerprint: format!(
+                "google/{codename}/{codename}:14/{BUILD_ID}/12025142:user/release-keys"
+            ),
+            build_id: BUILD_ID.into(),
+            build_time: 1722859200, // Aug 2024
+            sdk_version: 34,
+            hardware: codename.into(),
+            brand: "google".into(),
+            device: codename.into(),
+            product: codename.into(),
+            model: model.into(),
+            manufacturer: "Google".into(),
+            radio: radio.into(),
+            bootloader: bootloader.into(),
+            serial_number: random_serial_number(),
+            mac_address: random_mac_address(),
+            locale: default_locale(),
+            time_zone: default_time_zone(),
+        }
+    }
+}
+
+impl Default for DeviceProfile {
+    /// A Pixel 5 with a fresh serial number and MAC address
+    fn default() -> Self {
+        Self::pixel_5()
+    }
+}
+
+fn random_serial_number() -> String {
+    const ALPHABET: &[u8] = b"0123456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+
+    let mut rng = rand::rngs::OsRng;
+    (0..14)
+        .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+        .collect()
+}
+
+fn random_mac_address() -> String {
+    let mut mac: [u8; 6] = rand::rngs::OsRng.gen();
+    // unicast, locally administered like Android's randomized Wi-Fi MACs
+    mac[0] = (mac[0] & 0xfc) | 0x02;
+    mac.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn default_locale() -> String {
+    "en_US".into()
+}
+
+fn default_time_zone() -> String {
+    "America/Los_Angeles".into()
+}
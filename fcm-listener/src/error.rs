@@ -1,29 +1,286 @@
 use std::error;
 
+use reqwest::StatusCode;
+
 #[derive(Debug)]
 pub enum Error {
-    /// Dependency failed, i.e. we blame them
-    DependencyFailure(&'static str, &'static str),
-    /// Dependency rejection, i.e. they blame us
-    DependencyRejection(&'static str, String),
+    /// Server answered in a shape the client does not understand
+    MalformedResponse {
+        api: &'static str,
+        problem: &'static str,
+    },
     /// Protobuf deserialization failure, probably a contract change
     ProtobufDecode(&'static str, prost::DecodeError),
     Request(&'static str, reqwest::Error),
     Response(&'static str, reqwest::Error),
     Socket(std::io::Error),
+    /// Android checkin did not hand out usable credentials
+    Checkin(CheckinError),
+    /// GCM register3 answered with `Error=<reason>`
+    Register(&'static str, RegisterError),
+    /// Firebase Installations refused a request with this HTTP status and body
+    InstallationRejected(&'static str, StatusCode, String),
+    /// Push message could not be decrypted with the session keys
+    Decrypt(DecryptError),
+    /// MCS server refused the login request
+    LoginRejected(LoginError),
+    /// MCS server closed the stream instead of answering the login
+    LoginStreamError {
+        r#type: String,
+        text: Option<String>,
+    },
+    /// MCS server aborted an established stream with a StreamErrorStanza
+    StreamError {
+        r#type: String,
+        text: Option<String>,
+    },
+    /// Push encryption keys could not be generated
+    KeyGeneration(ece::Error),
+    /// Session has no such key, it was created before keys were generated
+    MissingKey(&'static str),
+    /// MCS host name is not a valid DNS name or IP address
+    InvalidMtalkHost(String),
+    /// Operation did not complete in time
+    Timeout(&'static str),
+    /// MCS server did not answer a client heartbeat, the connection is dead
+    HeartbeatTimeout,
+    /// Topic name is not valid for FCM
+    InvalidTopic(String),
+    /// Persistent ID store could not be read or written
+    Store(std::io::Error),
+    /// Proxy settings are not usable
+    InvalidProxy(String),
+    /// Proxy refused to open a tunnel to the MCS server
+    ProxyRejected(String),
+    /// Registration was made under a different android_id than the device
+    DeviceMismatch { device: i64, registration: i64 },
+}
+
+impl Error {
+    /// Whether repeating the same call later may succeed
+    ///
+    /// Covers transport failures, timeouts, rate limiting and server errors.
+    /// Anything else needs a different request, such as a fresh checkin or
+    /// corrected credentials.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Request(_, e) => !e.is_builder(),
+            Self::Response(_, _) | Self::Socket(_) => true,
+            Self::Timeout(_) | Self::HeartbeatTimeout => true,
+            // server shut the stream down, e.g. while draining for maintenance
+            Self::LoginStreamError { .. } | Self::StreamError { .. } => true,
+            Self::Checkin(CheckinError::Rejected(status, _)) => is_retryable_status(*status),
+            Self::Register(_, e) => e.is_retryable(),
+            Self::InstallationRejected(_, status, _) => is_retryable_status(*status),
+            Self::MalformedResponse { .. }
+            | Self::ProtobufDecode(_, _)
+            | Self::Checkin(_)
+            | Self::Decrypt(_)
+            | Self::LoginRejected(_)
+            | Self::KeyGeneration(_)
+            | Self::MissingKey(_)
+            | Self::InvalidMtalkHost(_)
+            | Self::InvalidTopic(_)
+            | Self::Store(_)
+            | Self::InvalidProxy(_)
+            | Self::ProxyRejected(_)
+            | Self::DeviceMismatch { .. } => false,
+        }
+    }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status.is_server_error()
+}
+
+/// Failure of the Android checkin request
+#[derive(Debug)]
+pub enum CheckinError {
+    /// Server answered with a non-success HTTP status and this body
+    Rejected(StatusCode, String),
+    MissingAndroidId,
+    MissingSecurityToken,
+    /// Android id does not fit in an i64
+    InvalidAndroidId(u64),
+}
+
+impl std::fmt::Display for CheckinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Rejected(status, body) => write!(f, "rejected with HTTP {status}: {body}"),
+            Self::MissingAndroidId => write!(f, "response is missing android id"),
+            Self::MissingSecurityToken => write!(f, "response is missing security token"),
+            Self::InvalidAndroidId(id) => write!(f, "responded with out of range android id {id}"),
+        }
+    }
+}
+
+/// Reason given by GCM register3 in an `Error=` response
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegisterError {
+    /// Usually the FIS token has not propagated yet, retrying shortly helps
+    PhoneRegistrationError,
+    /// android_id and security token were not accepted, check in again
+    AuthenticationFailed,
+    /// Sender ID does not belong to the app
+    InvalidSender,
+    /// Device has too many registrations, unregister some first
+    TooManyRegistrations,
+    /// Request format is no longer supported
+    DeprecatedEndpoint,
+    ServiceNotAvailable,
+    InternalServerError,
+    Other(String),
+}
+
+impl RegisterError {
+    pub fn from_reason(reason: &str) -> Self {
+        match reason.trim() {
+            "PHONE_REGISTRATION_ERROR" => Self::PhoneRegistrationError,
+            "AUTHENTICATION_FAILED" => Self::AuthenticationFailed,
+            "INVALID_SENDER" => Self::InvalidSender,
+            "TOO_MANY_REGISTRATIONS" => Self::TooManyRegistrations,
+            "DEPRECATED_ENDPOINT" => Self::DeprecatedEndpoint,
+            "SERVICE_NOT_AVAILABLE" => Self::ServiceNotAvailable,
+            "INTERNAL_SERVER_ERROR" => Self::InternalServerError,
+            other => Self::Other(other.into()),
+        }
+    }
+
+    /// The reason string as sent by GCM
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::PhoneRegistrationError => "PHONE_REGISTRATION_ERROR",
+            Self::AuthenticationFailed => "AUTHENTICATION_FAILED",
+            Self::InvalidSender => "INVALID_SENDER",
+            Self::TooManyRegistrations => "TOO_MANY_REGISTRATIONS",
+            Self::DeprecatedEndpoint => "DEPRECATED_ENDPOINT",
+            Self::ServiceNotAvailable => "SERVICE_NOT_AVAILABLE",
+            Self::InternalServerError => "INTERNAL_SERVER_ERROR",
+            Self::Other(reason) => reason,
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::PhoneRegistrationError | Self::ServiceNotAvailable | Self::InternalServerError
+        )
+    }
+}
+
+impl std::fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Failure to decrypt a push message
+#[derive(Debug)]
+pub enum DecryptError {
+    /// Session has no such key, it was created before keys were generated
+    MissingKey(&'static str),
+    /// Field is not valid base64
+    InvalidBase64(&'static str),
+    /// Message lacks a field the content encoding requires
+    MissingField(&'static str),
+    /// Message carries no encrypted payload
+    NotEncrypted,
+    Ece(ece::Error),
+}
+
+impl std::fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::MissingKey(key) => write!(f, "no {key} in session"),
+            Self::InvalidBase64(field) => write!(f, "invalid {field} base64"),
+            Self::MissingField(field) => write!(f, "message has no valid {field}"),
+            Self::NotEncrypted => write!(f, "message is not encrypted"),
+            Self::Ece(e) => write!(f, "ece error: {e}"),
+        }
+    }
+}
+
+/// Failure reported by the MCS server in a LoginResponse
+#[derive(Clone, Debug)]
+pub struct LoginError {
+    pub code: i32,
+    pub message: Option<String>,
+    pub error_type: Option<String>,
+}
+
+impl From<crate::mcs::ErrorInfo> for LoginError {
+    fn from(info: crate::mcs::ErrorInfo) -> Self {
+        Self {
+            code: info.code,
+            message: info.message,
+            error_type: info.r#type,
+        }
+    }
+}
+
+impl std::fmt::Display for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "code {}", self.code)?;
+        if let Some(error_type) = &self.error_type {
+            write!(f, " ({error_type})")?;
+        }
+        if let Some(message) = &self.message {
+            write!(f, ": {message}")?;
+        }
+        Ok(())
+    }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Self::DependencyFailure(api, problem) => write!(f, "{api} API {problem}"),
-            Self::DependencyRejection(api, reason) => {
-                write!(f, "{api} API rejected request: {reason}")
+            Self::MalformedResponse { api, problem } => {
+                write!(f, "{api} API sent a malformed response: {problem}")
             }
             Self::ProtobufDecode(kind, e) => write!(f, "Error decoding {kind}: {e}"),
             Self::Request(kind, e) => write!(f, "{kind} API request error: {e}"),
             Self::Response(kind, e) => write!(f, "{kind} API response error: {e}"),
             Self::Socket(e) => write!(f, "TCP error: {e}"),
+            Self::Checkin(e) => write!(f, "Android device check-in API {e}"),
+            Self::Register(api, e) => write!(f, "{api} API rejected request: {e}"),
+            Self::InstallationRejected(api, status, body) => {
+                write!(f, "{api} API rejected request with HTTP {status}: {body}")
+            }
+            Self::Decrypt(e) => write!(f, "FCM decryption failed: {e}"),
+            Self::LoginRejected(e) => write!(f, "MCS login rejected with {e}"),
+            Self::LoginStreamError { r#type, text } => {
+                write!(f, "MCS server aborted the login with stream error {type}")?;
+                if let Some(text) = text {
+                    write!(f, ": {text}")?;
+                }
+                Ok(())
+            }
+            Self::StreamError { r#type, text } => {
+                write!(f, "MCS server aborted the stream with stream error {type}")?;
+                if let Some(text) = text {
+                    write!(f, ": {text}")?;
+                }
+                Ok(())
+            }
+            Self::KeyGeneration(e) => write!(f, "Push key generation failed: {e}"),
+            Self::MissingKey(key) => write!(f, "Session has no {key}"),
+            Self::InvalidMtalkHost(host) => write!(f, "Invalid MCS host name {host:?}"),
+            Self::Timeout(operation) => write!(f, "{operation} timed out"),
+            Self::HeartbeatTimeout => write!(f, "MCS server did not acknowledge heartbeat"),
+            Self::InvalidTopic(topic) => write!(f, "Invalid FCM topic name {topic:?}"),
+            Self::Store(e) => write!(f, "Persistent ID store error: {e}"),
+            Self::InvalidProxy(reason) => write!(f, "Invalid proxy {reason}"),
+            Self::ProxyRejected(reason) => write!(f, "Proxy {reason}"),
+            Self::DeviceMismatch {
+                device,
+                registration,
+            } => write!(
+                f,
+                "Registration belongs to android_id {registration}, not to device {device}"
+            ),
         }
     }
 }
@@ -31,12 +288,29 @@ impl std::fmt::Display for Error {
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
-            Self::DependencyFailure(_, _) => None,
-            Self::DependencyRejection(_, _) => None,
+            Self::MalformedResponse { .. } => None,
             Self::ProtobufDecode(_, ref e) => Some(e),
             Self::Request(_, ref e) => Some(e),
             Self::Response(_, ref e) => Some(e),
             Self::Socket(ref e) => Some(e),
+            Self::Checkin(_) => None,
+            Self::Register(_, _) => None,
+            Self::InstallationRejected(_, _, _) => None,
+            Self::Decrypt(DecryptError::Ece(ref e)) => Some(e),
+            Self::Decrypt(_) => None,
+            Self::LoginRejected(_) => None,
+            Self::LoginStreamError { .. } => None,
+            Self::StreamError { .. } => None,
+            Self::KeyGeneration(ref e) => Some(e),
+            Self::MissingKey(_) => None,
+            Self::InvalidMtalkHost(_) => None,
+            Self::Timeout(_) => None,
+            Self::HeartbeatTimeout => None,
+            Self::InvalidTopic(_) => None,
+            Self::Store(ref e) => Some(e),
+            Self::InvalidProxy(_) => None,
+            Self::ProxyRejected(_) => None,
+            Self::DeviceMismatch { .. } => None,
         }
     }
 }
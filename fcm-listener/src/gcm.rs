@@ -1,8 +1,10 @@
+#[allow(clippy::enum_variant_names, clippy::doc_overindented_list_items)]
 pub mod contract {
     include!(concat!(env!("OUT_DIR"), "/checkin_proto.rs"));
 }
 
-use crate::Error;
+use crate::error::{CheckinError, DecryptError, RegisterError};
+use crate::{ClientConfig, ContentEncoding, DataMessage, DeviceProfile, Error, MtalkEndpoint};
 use base64::Engine;
 use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
@@ -10,20 +12,11 @@ use flate2::Compression;
 use prost::bytes::BufMut;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
+use std::time::Duration;
 use tokio_rustls::rustls::pki_types::ServerName;
 
-fn require_some<T>(value: Option<T>, reason: &'static str) -> Result<T, Error> {
-    match value {
-        Some(value) => Ok(value),
-        None => Err(Error::DependencyFailure("Android device check-in", reason)),
-    }
-}
-
-const CHECKIN_URL: &str = "https://android.clients.google.com/checkin";
-// microG uses android.clients.google.com
-const REGISTER_URL: &str = "https://android.clients.google.com/c2dm/register3";
-
 // Normal JSON serialization will lose precision and change the number, so we must
 // force the i64/u64 to serialize to string.
 #[serde_as]
@@ -46,52 +39,234 @@ pub struct GcmSession {
     /// Auth secret for decryption (base64 URL-safe, 16 bytes)
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub auth_secret: Option<String>,
+
+    /// Keys replaced by the last rotation, accepted until their grace period ends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_keys: Option<RetiredKeys>,
+
+    /// Device identity this session checked in as
+    #[serde(default = "DeviceProfile::legacy")]
+    pub device: DeviceProfile,
+
+    /// Settings digest returned by the last checkin, sent back on the next one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+
+    /// Server time of the last checkin in milliseconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_checkin_msec: Option<i64>,
+
+    /// Gservices settings table maintained across checkins
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub settings: BTreeMap<String, String>,
 }
 
+/// Digest sent on the first checkin, the same initial value microG uses
+const INITIAL_DIGEST: &str = "1-929a0dca0eee55513280171a8585da7dcd3700f8";
+
 impl GcmSession {
     /// Decrypt an encrypted FCM message payload
+    ///
+    /// `encrypted_base64` is an `aes128gcm` body, as found in the `encrypted`
+    /// app_data entry. Use [`GcmSession::decrypt_message`] to also handle `aesgcm`.
     pub fn decrypt(&self, encrypted_base64: &str) -> Result<Vec<u8>, Error> {
-        let private_key_b64 = self.private_key.as_ref().ok_or_else(|| {
-            Error::DependencyFailure("FCM decryption", "no private key in session")
-        })?;
-        let public_key_b64 = self.public_key.as_ref().ok_or_else(|| {
-            Error::DependencyFailure("FCM decryption", "no public key in session")
-        })?;
-        let auth_secret_b64 = self.auth_secret.as_ref().ok_or_else(|| {
-            Error::DependencyFailure("FCM decryption", "no auth secret in session")
-        })?;
-
         // Decode the encrypted payload (standard base64, may have / and +)
         let encrypted = base64::engine::general_purpose::STANDARD
             .decode(encrypted_base64)
-            .map_err(|_| Error::DependencyFailure("FCM decryption", "invalid base64 payload"))?;
+            .map_err(|_| Error::Decrypt(DecryptError::InvalidBase64("payload")))?;
 
-        // Decode private key (URL-safe base64)
-        let private_key_bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
-            .decode(private_key_b64)
-            .map_err(|_| Error::DependencyFailure("FCM decryption", "invalid private key base64"))?;
+        // Decrypt using ece (aes128gcm format used by modern FCM)
+        self.decrypt_with_keys(|ec_key, auth_secret| ece::decrypt(ec_key, auth_secret, &encrypted))
+    }
 
-        // Decode public key (URL-safe base64)
-        let public_key_bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
-            .decode(public_key_b64)
-            .map_err(|_| Error::DependencyFailure("FCM decryption", "invalid public key base64"))?;
+    /// Decrypt a Web Push encrypted data message
+    ///
+    /// Detects the scheme from the message: `aesgcm` takes its salt and sender
+    /// key from the `encryption` and `crypto-key` app_data entries, `aes128gcm`
+    /// carries them in the payload. The ciphertext is the base64 `encrypted`
+    /// app_data entry if present, the raw data otherwise.
+    pub fn decrypt_message(&self, message: &DataMessage) -> Result<Vec<u8>, Error> {
+        let encoding = message
+            .content_encoding()
+            .ok_or(Error::Decrypt(DecryptError::NotEncrypted))?;
+
+        let ciphertext = match message.get_app_data("encrypted") {
+            Some(encrypted) => decode_base64(encrypted)
+                .ok_or(Error::Decrypt(DecryptError::InvalidBase64("payload")))?,
+            None => message
+                .raw_data
+                .clone()
+                .ok_or(Error::Decrypt(DecryptError::MissingField("payload")))?,
+        };
 
-        // Decode auth secret (URL-safe base64)
-        let auth_secret_bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
-            .decode(auth_secret_b64)
-            .map_err(|_| Error::DependencyFailure("FCM decryption", "invalid auth secret base64"))?;
+        if encoding == ContentEncoding::Aes128Gcm {
+            return self.decrypt_with_keys(|ec_key, auth_secret| {
+                ece::decrypt(ec_key, auth_secret, &ciphertext)
+            });
+        }
 
-        // Create EcKeyComponents from private and public key
-        let ec_key = ece::crypto::EcKeyComponents::new(private_key_bytes, public_key_bytes);
+        let encryption = message.get_app_data("encryption").unwrap_or_default();
+        let crypto_key = message.get_app_data("crypto-key").unwrap_or_default();
+
+        let salt = header_param(encryption, "salt")
+            .ok_or(Error::Decrypt(DecryptError::MissingField("salt")))?;
+        let salt =
+            decode_base64(salt).ok_or(Error::Decrypt(DecryptError::InvalidBase64("salt")))?;
+        let rs = match header_param(encryption, "rs") {
+            Some(rs) => rs
+                .parse()
+                .map_err(|_| Error::Decrypt(DecryptError::MissingField("rs")))?,
+            None => 4096,
+        };
+        let dh = header_param(crypto_key, "dh")
+            .ok_or(Error::Decrypt(DecryptError::MissingField("dh")))?;
+        let dh = decode_base64(dh).ok_or(Error::Decrypt(DecryptError::InvalidBase64("dh")))?;
 
-        // Decrypt using ece (aes128gcm format used by modern FCM)
-        let decrypted = ece::decrypt(&ec_key, &auth_secret_bytes, &encrypted)
-            .map_err(|e| Error::DependencyFailure("FCM decryption", Box::leak(format!("ece error: {}", e).into_boxed_str())))?;
+        self.decrypt_with_keys(|ec_key, auth_secret| {
+            let block = ece::legacy::AesGcmEncryptedBlock::new(&dh, &salt, rs, ciphertext.clone())?;
+            ece::legacy::decrypt_aesgcm(ec_key, auth_secret, &block)
+        })
+    }
+
+    /// Run `decrypt` with the current keys, then with the retired keys while
+    /// they are still within their grace period
+    fn decrypt_with_keys(
+        &self,
+        decrypt: impl Fn(&ece::crypto::EcKeyComponents, &[u8]) -> Result<Vec<u8>, ece::Error>,
+    ) -> Result<Vec<u8>, Error> {
+        let (ec_key, auth_secret) = decode_keys(
+            self.private_key.as_deref(),
+            self.public_key.as_deref(),
+            self.auth_secret.as_deref(),
+        )?;
+        let error = match decrypt(&ec_key, &auth_secret) {
+            Ok(decrypted) => return Ok(decrypted),
+            Err(e) => e,
+        };
+
+        if let Some(previous) = self.previous_keys.as_ref().filter(|keys| !keys.is_expired()) {
+            let (ec_key, auth_secret) = decode_keys(
+                Some(&previous.private_key),
+                Some(&previous.public_key),
+                Some(&previous.auth_secret),
+            )?;
+            if let Ok(decrypted) = decrypt(&ec_key, &auth_secret) {
+                tracing::debug!("Decrypted FCM message with the retired keys");
+                return Ok(decrypted);
+            }
+        }
+
+        Err(Error::Decrypt(DecryptError::Ece(error)))
+    }
+
+    /// Replace the encryption keys, keeping the current ones for `grace`
+    ///
+    /// Messages encrypted for either key pair decrypt until the grace period
+    /// ends. Only one retired key pair is kept, rotating again replaces it.
+    /// The new public key only reaches senders once the token is registered
+    /// again, see [`Registration::rotate_keys`](crate::Registration::rotate_keys).
+    pub fn rotate_keys(&mut self, grace: Duration) -> Result<(), Error> {
+        let previous = match (&self.private_key, &self.public_key, &self.auth_secret) {
+            (Some(private_key), Some(public_key), Some(auth_secret)) => Some(RetiredKeys {
+                private_key: private_key.clone(),
+                public_key: public_key.clone(),
+                auth_secret: auth_secret.clone(),
+                expires_at: unix_now().as_secs() + grace.as_secs(),
+            }),
+            _ => None,
+        };
+        self.generate_keys()?;
+        self.previous_keys = previous;
+        Ok(())
+    }
+
+    /// Forget the retired keys once their grace period is over
+    pub fn drop_expired_keys(&mut self) {
+        if self.previous_keys.as_ref().is_some_and(RetiredKeys::is_expired) {
+            self.previous_keys = None;
+        }
+    }
+}
+
+/// Key pair and auth secret replaced by [`GcmSession::rotate_keys`]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RetiredKeys {
+    /// EC P-256 private key (base64 URL-safe, 32 bytes)
+    pub private_key: String,
+    /// EC P-256 public key (base64 URL-safe, 65 bytes uncompressed)
+    pub public_key: String,
+    /// Auth secret (base64 URL-safe, 16 bytes)
+    pub auth_secret: String,
+    /// End of the grace period, in seconds since the Unix epoch
+    pub expires_at: u64,
+}
 
-        Ok(decrypted)
+impl RetiredKeys {
+    pub fn is_expired(&self) -> bool {
+        unix_now().as_secs() >= self.expires_at
     }
 }
 
+/// Decode a key pair and auth secret stored as URL-safe base64
+fn decode_keys(
+    private_key: Option<&str>,
+    public_key: Option<&str>,
+    auth_secret: Option<&str>,
+) -> Result<(ece::crypto::EcKeyComponents, Vec<u8>), Error> {
+    let private_key_b64 =
+        private_key.ok_or(Error::Decrypt(DecryptError::MissingKey("private key")))?;
+    let public_key_b64 = public_key.ok_or(Error::Decrypt(DecryptError::MissingKey("public key")))?;
+    let auth_secret_b64 =
+        auth_secret.ok_or(Error::Decrypt(DecryptError::MissingKey("auth secret")))?;
+
+    // Decode private key (URL-safe base64)
+    let private_key_bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(private_key_b64)
+        .map_err(|_| Error::Decrypt(DecryptError::InvalidBase64("private key")))?;
+
+    // Decode public key (URL-safe base64)
+    let public_key_bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(public_key_b64)
+        .map_err(|_| Error::Decrypt(DecryptError::InvalidBase64("public key")))?;
+
+    // Decode auth secret (URL-safe base64)
+    let auth_secret_bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(auth_secret_b64)
+        .map_err(|_| Error::Decrypt(DecryptError::InvalidBase64("auth secret")))?;
+
+    // Create EcKeyComponents from private and public key
+    let ec_key = ece::crypto::EcKeyComponents::new(private_key_bytes, public_key_bytes);
+    Ok((ec_key, auth_secret_bytes))
+}
+
+/// Decode base64 in either alphabet, with or without padding
+fn decode_base64(value: &str) -> Option<Vec<u8>> {
+    let value: String = value
+        .trim()
+        .trim_end_matches('=')
+        .chars()
+        .map(|c| match c {
+            '+' => '-',
+            '/' => '_',
+            c => c,
+        })
+        .collect();
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(value)
+        .ok()
+}
+
+/// Find `name=value` in an `Encryption` or `Crypto-Key` header value
+///
+/// Parameters are separated by `;`, multiple keys in `Crypto-Key` by `,`.
+fn header_param<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header
+        .split([';', ','])
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().trim_matches('"'))
+}
+
 /// Token received from GCM registration
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GcmToken {
@@ -107,6 +282,104 @@ pub struct FirebaseInstallation {
     pub auth_token: String,
     /// Refresh token for obtaining new auth tokens
     pub refresh_token: String,
+    /// When the auth token expires, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_token_expires_at: Option<u64>,
+}
+
+impl FirebaseInstallation {
+    /// Whether the auth token is unknown, expired or expires within `margin`
+    pub fn auth_token_expires_within(&self, margin: Duration) -> bool {
+        match self.auth_token_expires_at {
+            Some(expires_at) => unix_now().as_secs() + margin.as_secs() >= expires_at,
+            None => true,
+        }
+    }
+}
+
+/// Prefix of topic scopes and of the `from` field of topic messages
+pub(crate) const TOPIC_PREFIX: &str = "/topics/";
+
+/// Strip an optional `/topics/` prefix and check the name the way the Firebase SDK does
+fn topic_name(topic: &str) -> Result<&str, Error> {
+    let name = topic.strip_prefix(TOPIC_PREFIX).unwrap_or(topic);
+    let valid = !name.is_empty()
+        && name.len() <= 900
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.~%".contains(&b));
+    if valid {
+        Ok(name)
+    } else {
+        Err(Error::InvalidTopic(topic.into()))
+    }
+}
+
+/// Copy a request for one more attempt, every request here has an in-memory body
+fn retry_request(request: &reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    request
+        .try_clone()
+        .expect("request bodies are buffered in memory")
+}
+
+/// Send a Firebase Installations request under the retry policy
+///
+/// Returns the body of a successful response.
+async fn send_fis_request(
+    config: &ClientConfig,
+    api_name: &'static str,
+    request: reqwest::RequestBuilder,
+) -> Result<String, Error> {
+    let request = &request;
+    config
+        .retry
+        .run(api_name, move || async move {
+            let response = retry_request(request)
+                .send()
+                .await
+                .map_err(|e| Error::Request(api_name, e))?;
+
+            let status = response.status();
+            let response_text = response
+                .text()
+                .await
+                .map_err(|e| Error::Response(api_name, e))?;
+
+            if !status.is_success() {
+                tracing::warn!("{} failed: {} - {}", api_name, status, response_text);
+                return Err(Error::InstallationRejected(api_name, status, response_text));
+            }
+            Ok(response_text)
+        })
+        .await
+}
+
+fn unix_now() -> Duration {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Parse an `authToken` object from the Firebase Installations API
+///
+/// Returns the token and its expiry in seconds since the Unix epoch. FIS
+/// reports the lifetime as a duration string such as `"604800s"`.
+fn parse_fis_auth_token(
+    api_name: &'static str,
+    auth_token: &serde_json::Value,
+) -> Result<(String, Option<u64>), Error> {
+    let token = auth_token["token"]
+        .as_str()
+        .ok_or(Error::MalformedResponse {
+            api: api_name,
+            problem: "missing authToken",
+        })?
+        .to_string();
+    let expires_at = auth_token["expiresIn"]
+        .as_str()
+        .and_then(|expires_in| expires_in.trim_end_matches('s').parse::<f64>().ok())
+        .map(|expires_in| unix_now().as_secs() + expires_in as u64);
+    Ok((token, expires_at))
 }
 
 /// Firebase app configuration needed for registration
@@ -123,11 +396,15 @@ pub struct FirebaseConfig {
 impl GcmSession {
     async fn request(
         http: &reqwest::Client,
-        android_id: Option<i64>,
-        security_token: Option<u64>,
+        config: &ClientConfig,
+        device: DeviceProfile,
+        previous: Option<&Self>,
     ) -> Result<Self, Error> {
         use prost::Message;
 
+        let android_id = previous.map(|session| session.android_id);
+        let security_token = previous.map(|session| session.security_token);
+
         // Current timestamp for event
         let now_ms = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
@@ -153,44 +430,49 @@ impl GcmSession {
         };
 
         // Use Android device type with proper Android build info
-        // This mimics what a real Android device (Pixel 5) would send
+        // This mimics what the real device in the profile would send
         let request = contract::AndroidCheckinRequest {
             version: Some(3),
             id: android_id,
             security_token,
             user_serial_number: Some(0),
             fragment: Some(if android_id.is_some() { 1 } else { 0 }),
-            locale: Some("en_US".into()),
-            time_zone: Some("America/Los_Angeles".into()),
+            locale: Some(device.locale.clone()),
+            time_zone: Some(device.time_zone.clone()),
             logging_id: Some(rand::random::<i64>().abs()),
-            // microG uses this specific initial digest value
-            digest: Some("1-929a0dca0eee55513280171a8585da7dcd3700f8".into()),
+            digest: Some(
+                previous
+                    .and_then(|session| session.digest.clone())
+                    .unwrap_or_else(|| INITIAL_DIGEST.into()),
+            ),
             ota_cert: vec!["71Q6Rn2DDZl1zPDVaaeEHItd".into()],
             account_cookie: vec!["".into()],
-            serial_number: Some("RF8M33YQXMR".into()),
-            mac_addr: vec!["aabbccddeeff".into()],
+            serial_number: Some(device.serial_number.clone()),
+            mac_addr: vec![device.mac_address.clone()],
             mac_addr_type: vec!["wifi".into()],
             checkin: contract::AndroidCheckinProto {
                 r#type: Some(1), // DEVICE_ANDROID_OS
                 build: Some(contract::AndroidBuildProto {
-                    fingerprint: Some(
-                        "google/redfin/redfin:14/AP2A.240805.005/12025142:user/release-keys".into(),
-                    ),
-                    hardware: Some("redfin".into()),
-                    brand: Some("google".into()),
-                    radio: Some("g7250-00217-231219-B-11446880".into()),
-                    bootloader: Some("slider-1.2-10323765".into()),
+                    fingerprint: Some(device.fingerprint.clone()),
+                    hardware: Some(device.hardware.clone()),
+                    brand: Some(device.brand.clone()),
+                    radio: Some(device.radio.clone()),
+                    bootloader: Some(device.bootloader.clone()),
                     client_id: Some("android-google".into()),
-                    time: Some(1722859200), // Aug 2024
-                    device: Some("redfin".into()),
-                    sdk_version: Some(34),
-                    model: Some("Pixel 5".into()),
-                    manufacturer: Some("Google".into()),
-                    product: Some("redfin".into()),
+                    time: Some(device.build_time),
+                    device: Some(device.device.clone()),
+                    sdk_version: Some(device.sdk_version),
+                    model: Some(device.model.clone()),
+                    manufacturer: Some(device.manufacturer.clone()),
+                    product: Some(device.product.clone()),
                     ota_installed: Some(false),
                     ..Default::default()
                 }),
-                last_checkin_msec: Some(0),
+                last_checkin_msec: Some(
+                    previous
+                        .and_then(|session| session.last_checkin_msec)
+                        .unwrap_or(0),
+                ),
                 event, // Add the event list (microG CheckinClient.java:108-112)
                 roaming: Some("WIFI::".into()),
                 user_number: Some(0),
@@ -202,17 +484,15 @@ impl GcmSession {
         const API_NAME: &str = "GCM checkin";
 
         // User-Agent matching microG's CheckinClient.java
-        let user_agent = "Android-Checkin/2.0 (redfin AP2A.240805.005); gzip";
+        let user_agent = device.checkin_user_agent();
 
         // Gzip compress the request body (both GMS and microG do this)
         let proto_bytes = request.encode_to_vec();
         let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
         encoder
             .write_all(&proto_bytes)
-            .map_err(|_| Error::DependencyFailure(API_NAME, "failed to gzip compress request"))?;
-        let compressed_body = encoder
-            .finish()
-            .map_err(|_| Error::DependencyFailure(API_NAME, "failed to finish gzip compression"))?;
+            .expect("writing to a Vec cannot fail");
+        let compressed_body = encoder.finish().expect("writing to a Vec cannot fail");
 
         tracing::debug!(
             "GCM checkin: compressed {} bytes -> {} bytes",
@@ -220,8 +500,8 @@ impl GcmSession {
             compressed_body.len()
         );
 
-        let response = http
-            .post(CHECKIN_URL)
+        let request = http
+            .post(&config.endpoints.checkin_url)
             .body(compressed_body)
             // Content-Type must be "application/x-protobuffer" (with 'buffer' suffix)
             // Both GMS (awzn.java:92) and microG use this exact value
@@ -229,29 +509,50 @@ impl GcmSession {
             // GMS and microG both send gzip-compressed bodies
             .header(reqwest::header::CONTENT_ENCODING, "gzip")
             .header(reqwest::header::ACCEPT_ENCODING, "gzip")
-            .header(reqwest::header::USER_AGENT, user_agent)
-            .send()
-            .await
-            .map_err(|e| Error::Request(API_NAME, e))?;
-
-        // Check if response is gzip-encoded and decompress if needed
-        let is_gzip = response
-            .headers()
-            .get(reqwest::header::CONTENT_ENCODING)
-            .map(|v| v.to_str().unwrap_or("").contains("gzip"))
-            .unwrap_or(false);
+            .header(reqwest::header::USER_AGENT, user_agent);
+
+        let request = &request;
+        let (is_gzip, response_bytes) = config
+            .retry
+            .run(API_NAME, move || async move {
+                let response = retry_request(request)
+                    .send()
+                    .await
+                    .map_err(|e| Error::Request(API_NAME, e))?;
+
+                let status = response.status();
+                if !status.is_success() {
+                    let body = response
+                        .text()
+                        .await
+                        .map_err(|e| Error::Response(API_NAME, e))?;
+                    return Err(Error::Checkin(CheckinError::Rejected(status, body)));
+                }
 
-        let response_bytes = response
-            .bytes()
-            .await
-            .map_err(|e| Error::Response(API_NAME, e))?;
+                // Check if response is gzip-encoded and decompress if needed
+                let is_gzip = response
+                    .headers()
+                    .get(reqwest::header::CONTENT_ENCODING)
+                    .map(|v| v.to_str().unwrap_or("").contains("gzip"))
+                    .unwrap_or(false);
+
+                let response_bytes = response
+                    .bytes()
+                    .await
+                    .map_err(|e| Error::Response(API_NAME, e))?;
+                Ok((is_gzip, response_bytes))
+            })
+            .await?;
 
         let decoded_bytes = if is_gzip {
             let mut decoder = GzDecoder::new(&response_bytes[..]);
             let mut decompressed = Vec::new();
             decoder
                 .read_to_end(&mut decompressed)
-                .map_err(|_| Error::DependencyFailure(API_NAME, "failed to decompress gzip response"))?;
+                .map_err(|_| Error::MalformedResponse {
+                    api: API_NAME,
+                    problem: "invalid gzip body",
+                })?;
             tracing::debug!(
                 "GCM checkin: decompressed {} bytes -> {} bytes",
                 response_bytes.len(),
@@ -265,17 +566,37 @@ impl GcmSession {
         let response = contract::AndroidCheckinResponse::decode(&decoded_bytes[..])
             .map_err(|e| Error::ProtobufDecode("android checkin response", e))?;
 
-        let android_id = require_some(response.android_id, "response is missing android id")?;
-
-        const BAD_ID: Result<i64, Error> = Err(Error::DependencyFailure(
-            API_NAME,
-            "responded with non-numeric android id",
-        ));
-        let android_id = i64::try_from(android_id).or(BAD_ID)?;
-        let security_token = require_some(
-            response.security_token,
-            "response is missing security token",
-        )?;
+        let android_id = response
+            .android_id
+            .ok_or(Error::Checkin(CheckinError::MissingAndroidId))?;
+        let android_id = i64::try_from(android_id)
+            .map_err(|_| Error::Checkin(CheckinError::InvalidAndroidId(android_id)))?;
+        let security_token = response
+            .security_token
+            .ok_or(Error::Checkin(CheckinError::MissingSecurityToken))?;
+
+        let mut settings = previous
+            .map(|session| session.settings.clone())
+            .unwrap_or_default();
+        match response.settings_diff {
+            // Only the changed keys were sent
+            Some(true) => {
+                for name in &response.delete_setting {
+                    settings.remove(name);
+                }
+            }
+            // The whole table was sent
+            Some(false) => settings.clear(),
+            // Leave the table alone unless this is the first checkin
+            None if previous.is_none() => settings.clear(),
+            None => {}
+        }
+        for setting in response.setting {
+            settings.insert(
+                String::from_utf8_lossy(&setting.name).into_owned(),
+                String::from_utf8_lossy(&setting.value).into_owned(),
+            );
+        }
 
         Ok(Self {
             android_id,
@@ -283,45 +604,73 @@ impl GcmSession {
             private_key: None,
             public_key: None,
             auth_secret: None,
+            previous_keys: None,
+            device,
+            digest: response
+                .digest
+                .or_else(|| previous.and_then(|session| session.digest.clone())),
+            last_checkin_msec: response.time_msec,
+            settings,
         })
     }
 
     /// Perform initial GCM checkin to get android_id and security_token
-    pub async fn checkin(http: &reqwest::Client) -> Result<Self, Error> {
-        let mut session = Self::request(http, None, None).await?;
+    pub async fn checkin(http: &reqwest::Client, config: &ClientConfig) -> Result<Self, Error> {
+        let device = config.device.clone().unwrap_or_default();
+        let mut session = Self::request(http, config, device, None).await?;
         // Generate encryption keys for this session
         session.generate_keys()?;
         Ok(session)
     }
 
+    /// How often the server wants this device to check in, if it said so
+    pub fn checkin_interval(&self) -> Option<Duration> {
+        self.settings
+            .get("checkin_interval")
+            .and_then(|secs| secs.parse::<u64>().ok())
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs)
+    }
+
     /// Refresh the session (re-checkin with existing credentials)
-    pub async fn refresh(&self, http: &reqwest::Client) -> Result<Self, Error> {
-        let mut session = Self::request(http, Some(self.android_id), Some(self.security_token)).await?;
+    pub async fn refresh(&self, http: &reqwest::Client, config: &ClientConfig) -> Result<Self, Error> {
+        let mut session = Self::request(http, config, self.device.clone(), Some(self)).await?;
         // Keep existing keys if we have them, otherwise generate new ones
         if self.private_key.is_some() {
             session.private_key = self.private_key.clone();
             session.public_key = self.public_key.clone();
             session.auth_secret = self.auth_secret.clone();
+            session.previous_keys = self.previous_keys.clone();
         } else {
             session.generate_keys()?;
         }
         Ok(session)
     }
 
+    /// Copy of this checkin with its own encryption keys, for another app on the same device
+    pub fn with_new_keys(&self) -> Result<Self, Error> {
+        let mut session = Self {
+            previous_keys: None,
+            ..self.clone()
+        };
+        session.generate_keys()?;
+        Ok(session)
+    }
+
     /// Generate EC P-256 key pair and auth secret for push encryption
     fn generate_keys(&mut self) -> Result<(), Error> {
         // Generate key pair and auth secret using ece crate
         let (local_key, auth_secret) = ece::generate_keypair_and_auth_secret()
-            .map_err(|_| Error::DependencyFailure("key generation", "failed to generate EC key pair"))?;
+            .map_err(Error::KeyGeneration)?;
 
         // Get raw key components
         let key_components = local_key.raw_components()
-            .map_err(|_| Error::DependencyFailure("key generation", "failed to extract key components"))?;
+            .map_err(Error::KeyGeneration)?;
 
         // Store private key, public key, and auth secret as base64
         self.private_key = Some(base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(key_components.private_key()));
         self.public_key = Some(base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(key_components.public_key()));
-        self.auth_secret = Some(base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&auth_secret));
+        self.auth_secret = Some(base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(auth_secret));
 
         tracing::debug!("Generated FCM encryption keys");
         Ok(())
@@ -329,9 +678,9 @@ impl GcmSession {
 
     /// Get the public key for registration (base64 URL-safe)
     pub fn get_public_key(&self) -> Result<String, Error> {
-        self.public_key.clone().ok_or_else(|| {
-            Error::DependencyFailure("public key", "no public key in session")
-        })
+        self.public_key
+            .clone()
+            .ok_or(Error::MissingKey("public key"))
     }
 
     /// Register with Firebase Installations to get FID and auth token
@@ -339,6 +688,7 @@ impl GcmSession {
     /// This is required for FCM registration with modern Firebase SDK (>= 20.1.1)
     pub async fn register_firebase_installation(
         http: &reqwest::Client,
+        config: &ClientConfig,
         firebase_config: &FirebaseConfig,
         package_name: &str,
         cert_sha1: &str,
@@ -369,8 +719,8 @@ impl GcmSession {
         };
 
         let url = format!(
-            "https://firebaseinstallations.googleapis.com/v1/projects/{}/installations",
-            firebase_config.project_id
+            "{}/projects/{}/installations",
+            config.endpoints.installations_url, firebase_config.project_id
         );
 
         let payload = serde_json::json!({
@@ -383,47 +733,38 @@ impl GcmSession {
         tracing::info!("Firebase Installations URL: {}", url);
         tracing::debug!("Firebase Installations payload: {:?}", payload);
 
-        let response = http
+        let request = http
             .post(&url)
             .header("Content-Type", "application/json")
             .header("x-goog-api-key", &firebase_config.api_key)
             .header("x-android-package", package_name)
             .header("x-android-cert", cert_sha1.to_uppercase())
-            .json(&payload)
-            .send()
-            .await
-            .map_err(|e| Error::Request(API_NAME, e))?;
-
-        let status = response.status();
-        let response_text = response
-            .text()
-            .await
-            .map_err(|e| Error::Response(API_NAME, e))?;
-
-        if !status.is_success() {
-            tracing::error!("Firebase Installations failed: {} - {}", status, response_text);
-            return Err(Error::DependencyRejection(
-                API_NAME,
-                format!("HTTP {}: {}", status, &response_text[..200.min(response_text.len())]),
-            ));
-        }
+            .json(&payload);
+        let response_text = send_fis_request(config, API_NAME, request).await?;
 
         let response_json: serde_json::Value = serde_json::from_str(&response_text)
-            .map_err(|_| Error::DependencyFailure(API_NAME, "invalid JSON response"))?;
+            .map_err(|_| Error::MalformedResponse {
+                api: API_NAME,
+                problem: "invalid JSON",
+            })?;
 
         let fid = response_json["fid"]
             .as_str()
-            .ok_or(Error::DependencyFailure(API_NAME, "missing fid in response"))?
+            .ok_or(Error::MalformedResponse {
+                api: API_NAME,
+                problem: "missing fid",
+            })?
             .to_string();
 
-        let auth_token = response_json["authToken"]["token"]
-            .as_str()
-            .ok_or(Error::DependencyFailure(API_NAME, "missing authToken in response"))?
-            .to_string();
+        let (auth_token, auth_token_expires_at) =
+            parse_fis_auth_token(API_NAME, &response_json["authToken"])?;
 
         let refresh_token = response_json["refreshToken"]
             .as_str()
-            .ok_or(Error::DependencyFailure(API_NAME, "missing refreshToken in response"))?
+            .ok_or(Error::MalformedResponse {
+                api: API_NAME,
+                problem: "missing refreshToken",
+            })?
             .to_string();
 
         tracing::info!("Firebase Installations succeeded, FID: {}", fid);
@@ -432,13 +773,108 @@ impl GcmSession {
             fid,
             auth_token,
             refresh_token,
+            auth_token_expires_at,
         })
     }
 
+    /// Get a new auth token for an existing Firebase Installation
+    ///
+    /// Uses the installation's refresh token, so the FID stays the same.
+    pub async fn generate_firebase_auth_token(
+        http: &reqwest::Client,
+        config: &ClientConfig,
+        firebase_config: &FirebaseConfig,
+        package_name: &str,
+        cert_sha1: &str,
+        installation: &FirebaseInstallation,
+    ) -> Result<FirebaseInstallation, Error> {
+        const API_NAME: &str = "Firebase Installations auth token";
+
+        let url = format!(
+            "{}/projects/{}/installations/{}/authTokens:generate",
+            config.endpoints.installations_url, firebase_config.project_id, installation.fid
+        );
+
+        let payload = serde_json::json!({
+            "installation": {
+                "sdkVersion": "a:17.0.0",
+            },
+        });
+
+        let request = http
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .header("x-goog-api-key", &firebase_config.api_key)
+            .header("x-android-package", package_name)
+            .header("x-android-cert", cert_sha1.to_uppercase())
+            .header(
+                reqwest::header::AUTHORIZATION,
+                format!("FIS_v2 {}", installation.refresh_token),
+            )
+            .json(&payload);
+        let response_text = send_fis_request(config, API_NAME, request).await?;
+
+        let response_json: serde_json::Value = serde_json::from_str(&response_text)
+            .map_err(|_| Error::MalformedResponse {
+                api: API_NAME,
+                problem: "invalid JSON",
+            })?;
+
+        // The response is the authToken object itself
+        let (auth_token, auth_token_expires_at) = parse_fis_auth_token(API_NAME, &response_json)?;
+
+        tracing::info!("Firebase auth token refreshed, FID: {}", installation.fid);
+
+        Ok(FirebaseInstallation {
+            fid: installation.fid.clone(),
+            auth_token,
+            refresh_token: installation.refresh_token.clone(),
+            auth_token_expires_at,
+        })
+    }
+
+    /// Delete a Firebase Installation
+    ///
+    /// An installation that no longer exists counts as deleted.
+    pub async fn delete_firebase_installation(
+        http: &reqwest::Client,
+        config: &ClientConfig,
+        firebase_config: &FirebaseConfig,
+        package_name: &str,
+        cert_sha1: &str,
+        installation: &FirebaseInstallation,
+    ) -> Result<(), Error> {
+        const API_NAME: &str = "Firebase Installations delete";
+
+        let url = format!(
+            "{}/projects/{}/installations/{}",
+            config.endpoints.installations_url, firebase_config.project_id, installation.fid
+        );
+
+        let request = http
+            .delete(&url)
+            .header("x-goog-api-key", &firebase_config.api_key)
+            .header("x-android-package", package_name)
+            .header("x-android-cert", cert_sha1.to_uppercase())
+            .header(
+                reqwest::header::AUTHORIZATION,
+                format!("FIS_v2 {}", installation.refresh_token),
+            );
+
+        match send_fis_request(config, API_NAME, request).await {
+            Ok(_) | Err(Error::InstallationRejected(_, reqwest::StatusCode::NOT_FOUND, _)) => {
+                tracing::info!("Firebase Installation deleted, FID: {}", installation.fid);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
     /// Register with GCM to get a token for receiving messages
     ///
     /// # Arguments
     /// * `http` - HTTP client
+    /// * `config` - Endpoints to register against
     /// * `sender_id` - Firebase sender ID (project number), e.g., "890224420307"
     /// * `package_name` - Android package name, e.g., "com.github.android"
     /// * `cert_sha1` - SHA1 of signing certificate (lowercase hex, no colons), or None
@@ -447,9 +883,11 @@ impl GcmSession {
     /// * `target_sdk` - Target SDK version from APK
     /// * `firebase_config` - Firebase configuration for Installations API
     /// * `firebase_installation` - Pre-registered Firebase Installation
+    #[allow(clippy::too_many_arguments)]
     pub async fn register(
         &self,
         http: &reqwest::Client,
+        config: &ClientConfig,
         sender_id: &str,
         package_name: &str,
         cert_sha1: Option<&str>,
@@ -460,11 +898,9 @@ impl GcmSession {
         firebase_installation: Option<&FirebaseInstallation>,
     ) -> Result<GcmToken, Error> {
         let android_id = self.android_id.to_string();
-        let auth_header = format!("AidLogin {}:{}", &android_id, &self.security_token);
-        let user_agent = "Android-GCM/1.5 (redfin AP2A.240805.005)";
 
         let app_ver_str = app_version.unwrap_or(1).to_string();
-        let target_ver_str = target_sdk.unwrap_or(34).to_string();
+        let target_ver_str = target_sdk.unwrap_or(self.device.sdk_version).to_string();
         let cert_str = cert_sha1.map(|c| c.to_lowercase()).unwrap_or_default();
         let ver_name_str = app_version_name.unwrap_or("1.0.0");
 
@@ -512,75 +948,210 @@ impl GcmSession {
         };
 
         const API_NAME: &str = "GCM registration";
-        // Aggressive retry: 15 attempts × 250ms = ~4s total window
-        // PHONE_REGISTRATION_ERROR is typically due to FIS token propagation delay
-        const MAX_RETRIES: u32 = 15;
-        const RETRY_DELAY_MS: u64 = 250;
 
         tracing::debug!("GCM register: {}", form_body);
 
-        let mut last_error = None;
+        // Response format is "token=<token>" or "Error=<reason>", PHONE_REGISTRATION_ERROR
+        // is typically due to FIS token propagation delay and retried by the policy
+        let response_text = self
+            .send_register_request(http, config, API_NAME, package_name, form_body)
+            .await?;
+        match response_text.strip_prefix("token=") {
+            Some(token) => Ok(GcmToken {
+                token: token.to_string(),
+            }),
+            None => {
+                tracing::warn!("Unexpected GCM response: {}", response_text);
+                Err(Error::MalformedResponse {
+                    api: API_NAME,
+                    problem: "unexpected body",
+                })
+            }
+        }
+    }
 
-        for attempt in 1..=MAX_RETRIES {
-            let result = http
-                .post(REGISTER_URL)
-                .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-                .header(reqwest::header::AUTHORIZATION, &auth_header)
-                .header(reqwest::header::USER_AGENT, user_agent)
-                .header("app", package_name)
-                .body(form_body.clone())
-                .send()
-                .await
-                .map_err(|e| Error::Request(API_NAME, e))?;
+    /// POST a form to register3 under the retry policy
+    ///
+    /// Returns the response body, or the reason of an `Error=<reason>` response
+    /// as [`Error::Register`].
+    async fn send_register_request(
+        &self,
+        http: &reqwest::Client,
+        config: &ClientConfig,
+        api_name: &'static str,
+        package_name: &str,
+        form_body: String,
+    ) -> Result<String, Error> {
+        let auth_header = format!("AidLogin {}:{}", self.android_id, self.security_token);
+        let request = http
+            .post(&config.endpoints.register_url)
+            .header(reqwest::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(reqwest::header::AUTHORIZATION, auth_header)
+            .header(reqwest::header::USER_AGENT, self.device.gcm_user_agent())
+            .header("app", package_name)
+            .body(form_body);
+
+        let request = &request;
+        config
+            .retry
+            .run(api_name, move || async move {
+                let response_text = retry_request(request)
+                    .send()
+                    .await
+                    .map_err(|e| Error::Request(api_name, e))?
+                    .text()
+                    .await
+                    .map_err(|e| Error::Response(api_name, e))?;
+
+                match response_text.strip_prefix("Error=") {
+                    Some(error) => Err(Error::Register(api_name, RegisterError::from_reason(error))),
+                    None => Ok(response_text),
+                }
+            })
+            .await
+    }
 
-            let response_text = result
-                .text()
-                .await
-                .map_err(|e| Error::Response(API_NAME, e))?;
+    /// Subscribe a registered token to a topic, or unsubscribe it
+    ///
+    /// Mirrors the Firebase SDK: a register3 call scoped to `/topics/<topic>`
+    /// with the token itself as sender. `topic` may include the `/topics/`
+    /// prefix.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn update_topic_subscription(
+        &self,
+        http: &reqwest::Client,
+        config: &ClientConfig,
+        token: &str,
+        topic: &str,
+        subscribe: bool,
+        package_name: &str,
+        cert_sha1: Option<&str>,
+        app_version: Option<i32>,
+        app_version_name: Option<&str>,
+        firebase_config: &FirebaseConfig,
+        firebase_installation: &FirebaseInstallation,
+    ) -> Result<(), Error> {
+        const API_NAME: &str = "GCM topic subscription";
 
-            // Response format is "token=<token>" or "Error=<reason>"
-            if let Some(token) = response_text.strip_prefix("token=") {
-                if attempt > 1 {
-                    tracing::info!("GCM registration succeeded on attempt {}", attempt);
-                }
-                return Ok(GcmToken {
-                    token: token.to_string(),
-                });
-            }
+        let topic = topic_name(topic)?;
+        let scope = format!("{TOPIC_PREFIX}{topic}");
 
-            if let Some(error) = response_text.strip_prefix("Error=") {
-                // Retry on PHONE_REGISTRATION_ERROR (transient timing issue)
-                if error == "PHONE_REGISTRATION_ERROR" && attempt < MAX_RETRIES {
-                    tracing::debug!("GCM registration attempt {}/{} failed, retrying...", attempt, MAX_RETRIES);
-                    last_error = Some(error.to_string());
-                    tokio::time::sleep(tokio::time::Duration::from_millis(RETRY_DELAY_MS)).await;
-                    continue;
-                }
-                return Err(Error::DependencyRejection(API_NAME, error.into()));
-            }
+        let android_id = self.android_id.to_string();
+        let app_ver_str = app_version.unwrap_or(1).to_string();
+        let cert_str = cert_sha1.map(|c| c.to_lowercase()).unwrap_or_default();
+        let ver_name_str = app_version_name.unwrap_or("1.0.0");
+
+        let mut form_body = format!(
+            "app={}&device={}&sender={}&cert={}&app_ver={}&X-appid={}&X-Goog-Firebase-Installations-Auth={}&X-cliv={}&X-gcm.topic={}&X-scope={}&X-subtype={}&X-gmp_app_id={}&X-app_ver_name={}",
+            urlencoding::encode(package_name),
+            urlencoding::encode(&android_id),
+            urlencoding::encode(token),
+            urlencoding::encode(&cert_str),
+            urlencoding::encode(&app_ver_str),
+            urlencoding::encode(&firebase_installation.fid),
+            urlencoding::encode(&firebase_installation.auth_token),
+            urlencoding::encode("fiid-21.0.0"),
+            urlencoding::encode(&scope),
+            urlencoding::encode(&scope),
+            urlencoding::encode(token),
+            urlencoding::encode(&firebase_config.app_id),
+            urlencoding::encode(ver_name_str),
+        );
+        if !subscribe {
+            form_body.push_str("&delete=true&X-delete=1");
+        }
+
+        let response_text = self
+            .send_register_request(http, config, API_NAME, package_name, form_body)
+            .await?;
+
+        // Subscriptions answer "token=<token>", unsubscriptions may answer "deleted=<app>"
+        if response_text.starts_with("token=") || response_text.starts_with("deleted=") {
+            tracing::info!(
+                "GCM topic {} {}",
+                scope,
+                if subscribe { "subscribed" } else { "unsubscribed" }
+            );
+            return Ok(());
+        }
+
+        tracing::warn!("Unexpected GCM topic response: {}", response_text);
+        Err(Error::MalformedResponse {
+            api: API_NAME,
+            problem: "unexpected body",
+        })
+    }
+
+    /// Delete a GCM registration so its token stops receiving messages
+    ///
+    /// Sends the same identity and auth header as [`GcmSession::register`],
+    /// with `delete=true`.
+    pub async fn unregister(
+        &self,
+        http: &reqwest::Client,
+        config: &ClientConfig,
+        sender_id: &str,
+        package_name: &str,
+        cert_sha1: Option<&str>,
+        firebase_installation: Option<&FirebaseInstallation>,
+    ) -> Result<(), Error> {
+        const API_NAME: &str = "GCM unregistration";
+
+        let android_id = self.android_id.to_string();
+        let cert_str = cert_sha1.map(|c| c.to_lowercase()).unwrap_or_default();
+
+        let mut form_body = format!(
+            "app={}&device={}&sender={}&cert={}&delete=true&X-scope={}&X-subtype={}",
+            urlencoding::encode(package_name),
+            urlencoding::encode(&android_id),
+            urlencoding::encode(sender_id),
+            urlencoding::encode(&cert_str),
+            urlencoding::encode("*"),
+            urlencoding::encode(sender_id),
+        );
+        if let Some(fis) = firebase_installation {
+            form_body.push_str(&format!(
+                "&X-appid={}&X-Goog-Firebase-Installations-Auth={}",
+                urlencoding::encode(&fis.fid),
+                urlencoding::encode(&fis.auth_token),
+            ));
+        }
 
-            tracing::warn!("Unexpected GCM response: {}", response_text);
-            return Err(Error::DependencyFailure(API_NAME, "malformed response"));
+        // Response format is "deleted=<app>" or "Error=<reason>"
+        let response_text = self
+            .send_register_request(http, config, API_NAME, package_name, form_body)
+            .await?;
+        if response_text.starts_with("deleted=") {
+            return Ok(());
         }
 
-        Err(Error::DependencyRejection(
-            API_NAME,
-            last_error.unwrap_or_else(|| "max retries exceeded".into()),
-        ))
+        tracing::warn!("Unexpected GCM unregister response: {}", response_text);
+        Err(Error::MalformedResponse {
+            api: API_NAME,
+            problem: "unexpected body",
+        })
     }
 
     /// Connect to mtalk.google.com MCS server
-    pub async fn connect(&self, received_persistent_id: Vec<String>) -> Result<Connection, Error> {
+    ///
+    /// Completes only once the server has answered the login with a successful
+    /// `LoginResponse`, so a revoked android_id or stale security token surfaces
+    /// here as [`Error::LoginRejected`] instead of as a silently closed stream.
+    ///
+    /// The servers of [`Endpoints::mtalk_candidates`](crate::Endpoints::mtalk_candidates)
+    /// are tried in order until one can be reached, each within
+    /// [`ClientConfig::mtalk_attempt_timeout`]. [`Connection::endpoint`] tells
+    /// which one answered.
+    pub async fn connect(
+        &self,
+        config: &ClientConfig,
+        received_persistent_id: Vec<String>,
+    ) -> Result<Connection, Error> {
         use prost::Message;
 
         // Install the default crypto provider if not already installed
         let _ = rustls::crypto::ring::default_provider().install_default();
 
-        const ERR_RESOLVE: Error =
-            Error::DependencyFailure("name resolution", "unable to resolve google talk host name");
-
-        let domain = ServerName::try_from("mtalk.google.com").or(Err(ERR_RESOLVE))?;
-
         let login_request = self.new_mcs_login_request(received_persistent_id);
 
         let mut login_bytes = bytes::BytesMut::with_capacity(2 + login_request.encoded_len() + 4);
@@ -590,13 +1161,41 @@ impl GcmSession {
             .encode_length_delimited(&mut login_bytes)
             .expect("login request encoding failure");
 
-        Self::try_connect(domain, &login_bytes)
+        let mut last_error = None;
+        for (attempt, endpoint) in config.endpoints.mtalk_candidates().into_iter().enumerate() {
+            let domain = ServerName::try_from(endpoint.host.clone())
+                .map_err(|_| Error::InvalidMtalkHost(endpoint.host.clone()))?;
+            let result = tokio::time::timeout(
+                config.mtalk_attempt_timeout,
+                Self::try_connect(config, &endpoint, domain, &login_bytes),
+            )
             .await
-            .map_err(Error::Socket)
+            .unwrap_or(Err(Error::Timeout("MCS login")));
+
+            match result {
+                Ok(connection) => {
+                    if attempt > 0 {
+                        tracing::info!("Connected to fallback MCS server {}", endpoint);
+                    }
+                    return Ok(connection);
+                }
+                // unreachable from here, the next server may not be
+                Err(e @ (Error::Socket(_) | Error::Timeout(_) | Error::ProxyRejected(_))) => {
+                    tracing::warn!("Could not reach MCS server {}: {}", endpoint, e);
+                    last_error = Some(e);
+                }
+                // the server answered, another one would answer the same
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(last_error.expect("mtalk_candidates always holds the primary server"))
     }
 
     const MCS_VERSION: u8 = 41;
     const LOGIN_REQUEST_TAG: u8 = 2;
+    const LOGIN_RESPONSE_TAG: u8 = 3;
+    const STREAM_ERROR_TAG: u8 = 10;
 
     fn new_mcs_login_request(
         &self,
@@ -624,48 +1223,212 @@ impl GcmSession {
     }
 
     async fn try_connect(
+        config: &ClientConfig,
+        endpoint: &MtalkEndpoint,
         domain: ServerName<'static>,
         login_bytes: &[u8],
-    ) -> Result<Connection, tokio::io::Error> {
+    ) -> Result<Connection, Error> {
+        use prost::Message;
         use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-        let stream = tokio::net::TcpStream::connect("mtalk.google.com:5228").await?;
-        let tls = new_tls_initiator();
-        let mut stream = tls.connect(domain, stream).await?;
+        const API_NAME: &str = "MCS login";
+
+        let stream = match &config.proxy {
+            Some(proxy) => proxy.connect(&endpoint.host, endpoint.port).await?,
+            None => crate::net::connect_tcp(&endpoint.host, endpoint.port)
+                .await
+                .map_err(Error::Socket)?,
+        };
+        let tls = config.tls_connector();
+        let mut stream = tls.connect(domain, stream).await.map_err(Error::Socket)?;
 
-        stream.write_all(login_bytes).await?;
+        stream.write_all(login_bytes).await.map_err(Error::Socket)?;
 
         // Read the version byte from server
-        stream.read_i8().await?;
+        stream.read_i8().await.map_err(Error::Socket)?;
 
-        Ok(Connection(stream))
-    }
-}
+        let (tag, payload) = crate::push::read_frame(&mut stream)
+            .await
+            .map_err(Error::Socket)?;
+
+        match tag {
+            Self::LOGIN_RESPONSE_TAG => {}
+            Self::STREAM_ERROR_TAG => {
+                let stream_error = crate::mcs::StreamErrorStanza::decode(payload)
+                    .map_err(|e| Error::ProtobufDecode("MCS stream error", e))?;
+                return Err(Error::LoginStreamError {
+                    r#type: stream_error.r#type,
+                    text: stream_error.text,
+                });
+            }
+            _ => {
+                return Err(Error::MalformedResponse {
+                    api: API_NAME,
+                    problem: "unexpected stanza before the LoginResponse",
+                })
+            }
+        }
+
+        let response = crate::mcs::LoginResponse::decode(payload)
+            .map_err(|e| Error::ProtobufDecode("MCS login response", e))?;
 
-fn new_tls_initiator() -> tokio_rustls::TlsConnector {
-    let root_store = tokio_rustls::rustls::RootCertStore {
-        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
-    };
+        if let Some(error) = response.error {
+            return Err(Error::LoginRejected(error.into()));
+        }
+
+        tracing::debug!(
+            "MCS login accepted: stream_id={:?}, server_timestamp={:?}",
+            response.stream_id,
+            response.server_timestamp
+        );
 
-    let config = tokio_rustls::rustls::ClientConfig::builder()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
+        Ok(Connection {
+            stream,
+            endpoint: endpoint.clone(),
+            stream_id: response.stream_id,
+            heartbeat_config: response.heartbeat_config,
+            server_timestamp: response.server_timestamp,
+            settings: response.setting,
+        })
+    }
+}
 
-    tokio_rustls::TlsConnector::from(std::sync::Arc::new(config))
+/// An MCS connection that has completed the login handshake
+pub struct Connection {
+    /// TLS stream, positioned right after the LoginResponse
+    pub stream: tokio_rustls::client::TlsStream<tokio::net::TcpStream>,
+    /// MCS server the connection was made to
+    pub endpoint: MtalkEndpoint,
+    /// Stream ID the server assigned to this connection
+    pub stream_id: Option<i32>,
+    /// Heartbeat settings requested by the server
+    pub heartbeat_config: Option<crate::mcs::HeartbeatConfig>,
+    /// Server time in milliseconds since the Unix epoch
+    pub server_timestamp: Option<i64>,
+    /// Settings pushed by the server with the LoginResponse
+    pub settings: Vec<crate::mcs::Setting>,
 }
 
-pub struct Connection(pub tokio_rustls::client::TlsStream<tokio::net::TcpStream>);
+impl Connection {
+    /// Heartbeat interval requested by the server, if any
+    pub fn heartbeat_interval(&self) -> Option<std::time::Duration> {
+        self.heartbeat_config
+            .as_ref()
+            .and_then(|config| config.interval_ms)
+            .filter(|interval_ms| *interval_ms > 0)
+            .map(|interval_ms| std::time::Duration::from_millis(interval_ms as u64))
+    }
+}
 
 impl std::ops::Deref for Connection {
     type Target = tokio_rustls::client::TlsStream<tokio::net::TcpStream>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.stream
     }
 }
 
 impl std::ops::DerefMut for Connection {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.stream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session_with_keys() -> GcmSession {
+        let mut session = GcmSession {
+            android_id: 4242,
+            security_token: 77,
+            private_key: None,
+            public_key: None,
+            auth_secret: None,
+            previous_keys: None,
+            device: DeviceProfile::default(),
+            digest: None,
+            last_checkin_msec: None,
+            settings: BTreeMap::new(),
+        };
+        session.generate_keys().unwrap();
+        session
+    }
+
+    /// The public key and auth secret a sender encrypts to
+    fn recipient(session: &GcmSession) -> (Vec<u8>, Vec<u8>) {
+        (
+            decode_base64(session.public_key.as_deref().unwrap()).unwrap(),
+            decode_base64(session.auth_secret.as_deref().unwrap()).unwrap(),
+        )
+    }
+
+    /// Encrypt like a legacy sender, with the salt and sender key in app_data
+    fn aesgcm_message(session: &GcmSession, plaintext: &[u8]) -> DataMessage {
+        let (public_key, auth_secret) = recipient(session);
+        let block = ece::legacy::encrypt_aesgcm(&public_key, &auth_secret, plaintext).unwrap();
+        let mut app_data = vec![("content-encoding".to_string(), "aesgcm".to_string())];
+        for (name, value) in block.headers(None) {
+            app_data.push((name.to_lowercase(), value));
+        }
+        DataMessage {
+            raw_data: decode_base64(&block.body()),
+            app_data,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn decrypts_aesgcm_message() {
+        let session = session_with_keys();
+        let message = aesgcm_message(&session, b"legacy payload");
+        assert_eq!(message.content_encoding(), Some(ContentEncoding::AesGcm));
+        assert_eq!(session.decrypt_message(&message).unwrap(), b"legacy payload");
+    }
+
+    #[test]
+    fn aesgcm_message_without_salt_or_dh_is_rejected() {
+        let session = session_with_keys();
+        for (header, field) in [("encryption", "salt"), ("crypto-key", "dh")] {
+            let mut message = aesgcm_message(&session, b"legacy payload");
+            for (name, value) in &mut message.app_data {
+                if name == header {
+                    *value = "rs=4096".into();
+                }
+            }
+            match session.decrypt_message(&message) {
+                Err(Error::Decrypt(DecryptError::MissingField(missing))) => {
+                    assert_eq!(missing, field)
+                }
+                other => panic!("expected missing {field}, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn retired_keys_decrypt_only_during_grace() {
+        let mut session = session_with_keys();
+        let (public_key, auth_secret) = recipient(&session);
+        let ciphertext = ece::encrypt(&public_key, &auth_secret, b"sent before rotation").unwrap();
+        let message = DataMessage {
+            raw_data: Some(ciphertext),
+            app_data: vec![("content-encoding".into(), "aes128gcm".into())],
+            ..Default::default()
+        };
+
+        let mut rotated = session.clone();
+        rotated.rotate_keys(Duration::from_secs(60 * 60)).unwrap();
+        assert_ne!(rotated.public_key, session.public_key);
+        assert!(rotated.previous_keys.as_ref().is_some_and(|keys| !keys.is_expired()));
+        assert_eq!(rotated.decrypt_message(&message).unwrap(), b"sent before rotation");
+
+        // a grace period of zero is over right away
+        session.rotate_keys(Duration::ZERO).unwrap();
+        assert!(matches!(
+            session.decrypt_message(&message),
+            Err(Error::Decrypt(DecryptError::Ece(_)))
+        ));
+        session.drop_expired_keys();
+        assert!(session.previous_keys.is_none());
     }
 }
@@ -0,0 +1,303 @@
+use crate::{ClientConfig, Connection, DataMessage, Error, FcmCredentials, GcmSession, Registration};
+use std::sync::{Arc, RwLock};
+
+/// One Android device shared by many app registrations
+///
+/// A phone checks in once and keeps a single MCS connection for all of its
+/// apps. A `GcmDevice` does the same: every app registered through it gets its
+/// own encryption keys and FCM token under the device's android_id, and
+/// incoming messages are matched back to their registration with
+/// [`GcmDevice::route`]. Registrations can be added and removed while the
+/// device is connected, see [`FcmClient::listen_device`](crate::FcmClient::listen_device).
+pub struct GcmDevice {
+    session: GcmSession,
+    registrations: RwLock<Vec<Arc<Registration>>>,
+}
+
+impl GcmDevice {
+    /// Device for an existing checkin, e.g. one restored from storage
+    pub fn new(session: GcmSession) -> Self {
+        Self {
+            session,
+            registrations: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Check in as a new device
+    pub async fn checkin(http: &reqwest::Client, config: &ClientConfig) -> Result<Self, Error> {
+        let session = GcmSession::checkin(http, config).await?;
+        tracing::info!("GCM checkin complete: android_id={}", session.android_id);
+        Ok(Self::new(session))
+    }
+
+    /// Device holding a single registration under that registration's checkin
+    pub fn from_registration(registration: Registration) -> Self {
+        let device = Self::new(registration.gcm_session.clone());
+        device
+            .insert(registration)
+            .expect("registration shares the device checkin");
+        device
+    }
+
+    /// The checkin shared by all registrations, used for the MCS login
+    pub fn session(&self) -> &GcmSession {
+        &self.session
+    }
+
+    /// Register an app on this device and add it to the routing table
+    pub async fn register(
+        &self,
+        http: &reqwest::Client,
+        config: &ClientConfig,
+        creds: &FcmCredentials,
+    ) -> Result<Arc<Registration>, Error> {
+        let session = self.session.with_new_keys()?;
+        let registration = Registration::register_with_session(http, config, session, creds).await?;
+        self.insert(registration)
+    }
+
+    /// Add a registration made on this device, replacing the one for the same
+    /// package and sender
+    ///
+    /// Use this to restore saved registrations and to swap in one that was
+    /// refreshed or had its keys rotated.
+    pub fn insert(&self, registration: Registration) -> Result<Arc<Registration>, Error> {
+        if registration.gcm_session.android_id != self.session.android_id {
+            return Err(Error::DeviceMismatch {
+                device: self.session.android_id,
+                registration: registration.gcm_session.android_id,
+            });
+        }
+
+        let registration = Arc::new(registration);
+        let mut registrations = self.registrations.write().expect("lock poisoned");
+        registrations.retain(|existing| !same_app(existing, &registration));
+        registrations.push(registration.clone());
+        Ok(registration)
+    }
+
+    /// Stop routing messages to an app, returning its registration
+    ///
+    /// The FCM token stays valid until it is deleted with [`Registration::unregister`].
+    pub fn remove(&self, package_name: &str, sender_id: &str) -> Option<Arc<Registration>> {
+        let mut registrations = self.registrations.write().expect("lock poisoned");
+        let index = registrations.iter().position(|registration| {
+            registration.credentials.package_name == package_name
+                && registration.credentials.sender_id == sender_id
+        })?;
+        Some(registrations.remove(index))
+    }
+
+    /// Snapshot of the registrations on this device
+    pub fn registrations(&self) -> Vec<Arc<Registration>> {
+        self.registrations.read().expect("lock poisoned").clone()
+    }
+
+    /// The registration a message was sent to
+    ///
+    /// Matches the `to` token first, then the package in `category`. When one
+    /// package is registered for several senders, `from` picks the sender or,
+    /// for topic messages, a registration subscribed to the topic, and the
+    /// message is unrouted when neither matches. Messages without `to` and
+    /// `category` go to the registration of a device that has just one, any
+    /// other message no registration matches is unrouted.
+    pub fn route(&self, message: &DataMessage) -> Option<Arc<Registration>> {
+        let registrations = self.registrations.read().expect("lock poisoned");
+
+        if let Some(to) = message.to.as_deref() {
+            if let Some(registration) = registrations.iter().find(|r| r.fcm_token() == to) {
+                return Some(registration.clone());
+            }
+        }
+
+        let for_package: Vec<_> = registrations
+            .iter()
+            .filter(|r| message.category.as_deref() == Some(r.credentials.package_name.as_str()))
+            .collect();
+        let registration = match for_package.as_slice() {
+            [] if message.to.is_none() && message.category.is_none() && registrations.len() == 1 => {
+                registrations.first()
+            }
+            [] => None,
+            [registration] => Some(*registration),
+            // guessing would decrypt with another registration's keys
+            _ => for_package
+                .iter()
+                .find(|r| {
+                    message.from.as_deref() == Some(r.credentials.sender_id.as_str())
+                        || message
+                            .topic()
+                            .is_some_and(|topic| r.topics.iter().any(|t| t == topic))
+                })
+                .copied(),
+        };
+        registration.cloned()
+    }
+
+    /// Log in to MCS as this device
+    pub async fn connect(
+        &self,
+        config: &ClientConfig,
+        persistent_ids: Vec<String>,
+    ) -> Result<Connection, Error> {
+        self.session.connect(config, persistent_ids).await
+    }
+}
+
+fn same_app(a: &Registration, b: &Registration) -> bool {
+    a.credentials.package_name == b.credentials.package_name
+        && a.credentials.sender_id == b.credentials.sender_id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeviceProfile, GcmToken};
+
+    const ANDROID_ID: i64 = 4242;
+
+    fn session(android_id: i64) -> GcmSession {
+        GcmSession {
+            android_id,
+            security_token: 77,
+            private_key: None,
+            public_key: None,
+            auth_secret: None,
+            previous_keys: None,
+            device: DeviceProfile::default(),
+            digest: None,
+            last_checkin_msec: None,
+            settings: Default::default(),
+        }
+    }
+
+    fn registration(package_name: &str, sender_id: &str, token: &str) -> Registration {
+        Registration {
+            gcm_session: session(ANDROID_ID),
+            gcm_token: GcmToken {
+                token: token.into(),
+            },
+            credentials: FcmCredentials {
+                sender_id: sender_id.into(),
+                api_key: "key".into(),
+                app_id: format!("1:{sender_id}:android:abc"),
+                project_id: "project".into(),
+                package_name: package_name.into(),
+                cert_sha1: None,
+                app_version: None,
+                app_version_name: None,
+                target_sdk: None,
+            },
+            firebase_installation: None,
+            topics: Vec::new(),
+        }
+    }
+
+    fn routed_token(device: &GcmDevice, message: &DataMessage) -> Option<String> {
+        device
+            .route(message)
+            .map(|registration| registration.fcm_token().to_string())
+    }
+
+    #[test]
+    fn routes_by_token_then_package() {
+        let device = GcmDevice::new(session(ANDROID_ID));
+        device.insert(registration("com.example.a", "1", "token-a")).unwrap();
+        device.insert(registration("com.example.b", "2", "token-b")).unwrap();
+
+        let to_b = DataMessage {
+            to: Some("token-b".into()),
+            category: Some("com.example.a".into()),
+            ..Default::default()
+        };
+        assert_eq!(routed_token(&device, &to_b).as_deref(), Some("token-b"));
+
+        let for_a = DataMessage {
+            category: Some("com.example.a".into()),
+            ..Default::default()
+        };
+        assert_eq!(routed_token(&device, &for_a).as_deref(), Some("token-a"));
+
+        let unknown = DataMessage {
+            category: Some("com.example.c".into()),
+            ..Default::default()
+        };
+        assert_eq!(routed_token(&device, &unknown), None);
+        assert_eq!(routed_token(&device, &DataMessage::default()), None);
+    }
+
+    #[test]
+    fn picks_sender_or_topic_within_a_package() {
+        let device = GcmDevice::new(session(ANDROID_ID));
+        device.insert(registration("com.example", "1", "token-1")).unwrap();
+        let mut subscribed = registration("com.example", "2", "token-2");
+        subscribed.topics.push("news".into());
+        device.insert(subscribed).unwrap();
+
+        let message = |from: Option<&str>| DataMessage {
+            category: Some("com.example".into()),
+            from: from.map(Into::into),
+            ..Default::default()
+        };
+        assert_eq!(routed_token(&device, &message(Some("1"))).as_deref(), Some("token-1"));
+        assert_eq!(
+            routed_token(&device, &message(Some("/topics/news"))).as_deref(),
+            Some("token-2")
+        );
+        // decrypting with either registration's keys would be a guess
+        assert_eq!(routed_token(&device, &message(Some("/topics/sports"))), None);
+        assert_eq!(routed_token(&device, &message(None)), None);
+    }
+
+    #[test]
+    fn single_registration_gets_only_messages_without_target() {
+        let device = GcmDevice::from_registration(registration("com.example", "1", "token-1"));
+
+        assert_eq!(
+            routed_token(&device, &DataMessage::default()).as_deref(),
+            Some("token-1")
+        );
+        let other_package = DataMessage {
+            category: Some("com.example.other".into()),
+            ..Default::default()
+        };
+        assert_eq!(routed_token(&device, &other_package), None);
+        let other_token = DataMessage {
+            to: Some("token-of-a-deleted-registration".into()),
+            ..Default::default()
+        };
+        assert_eq!(routed_token(&device, &other_token), None);
+    }
+
+    #[test]
+    fn insert_replaces_same_app_and_rejects_other_devices() {
+        let device = GcmDevice::new(session(ANDROID_ID));
+        device.insert(registration("com.example", "1", "old")).unwrap();
+        device.insert(registration("com.example", "2", "other-sender")).unwrap();
+        device.insert(registration("com.example", "1", "new")).unwrap();
+
+        let mut tokens: Vec<_> = device
+            .registrations()
+            .iter()
+            .map(|registration| registration.fcm_token().to_string())
+            .collect();
+        tokens.sort();
+        assert_eq!(tokens, ["new", "other-sender"]);
+
+        let mut foreign = registration("com.example.foreign", "1", "foreign");
+        foreign.gcm_session.android_id = ANDROID_ID + 1;
+        assert!(matches!(
+            device.insert(foreign),
+            Err(Error::DeviceMismatch {
+                device: ANDROID_ID,
+                registration,
+            }) if registration == ANDROID_ID + 1
+        ));
+
+        assert_eq!(
+            device.remove("com.example", "2").map(|r| r.fcm_token().to_string()).as_deref(),
+            Some("other-sender")
+        );
+        assert!(device.remove("com.example", "2").is_none());
+    }
+}
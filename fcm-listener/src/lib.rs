@@ -6,40 +6,75 @@
 //! ## Usage
 //!
 //! ```rust,no_run
-//! use fcm_listener::{FcmCredentials, Registration};
+//! use fcm_listener::{ClientConfig, FcmCredentials, Registration};
 //!
 //! #[tokio::main]
 //! async fn main() -> anyhow::Result<()> {
-//!     let http = reqwest::Client::new();
+//!     let config = ClientConfig::default();
+//!     let http = config.http_client()?;
 //!     let creds = FcmCredentials {
 //!         sender_id: "123456789".into(),
 //!         api_key: "AIza...".into(),
 //!         app_id: "1:123456789:android:abc123".into(),
 //!         project_id: "my-project".into(),
 //!         package_name: "com.example.app".into(),
+//!         cert_sha1: None,
+//!         app_version: None,
+//!         app_version_name: None,
+//!         target_sdk: None,
 //!     };
 //!
-//!     let registration = Registration::register(&http, &creds).await?;
+//!     let registration = Registration::register(&http, &config, &creds).await?;
 //!     println!("FCM Token: {}", registration.fcm_token());
 //!
-//!     let mut stream = registration.connect(vec![]).await?;
+//!     let mut stream = registration.connect(&config, vec![]).await?;
 //!     // Use tokio_stream::StreamExt to receive messages
 //!
 //!     Ok(())
 //! }
 //! ```
 
-mod mcs {
+/// Protobuf types of the MCS protocol spoken with mtalk.google.com
+pub mod mcs {
     include!(concat!(env!("OUT_DIR"), "/mcs_proto.rs"));
 }
 
+/// Protobuf types of the Android checkin API, for scripting [`test_support`] checkins
+#[cfg(feature = "test-support")]
+pub use gcm::contract as checkin;
+
+mod client;
+mod config;
+mod device;
 mod error;
 mod gcm;
+mod gcm_device;
+mod net;
+mod payload;
+mod proxy;
 mod push;
+mod retry;
+mod store;
+#[cfg(feature = "test-support")]
+pub mod test_support;
 
-pub use error::Error;
-pub use gcm::{Connection, FirebaseConfig, FirebaseInstallation, GcmSession, GcmToken};
-pub use push::{new_heartbeat_ack, DataMessage, Message, MessageStream, MessageTag};
+pub use client::{Event, FcmClient, ListenOptions, Listener};
+pub use config::{ClientConfig, Endpoints, MtalkEndpoint};
+pub use device::DeviceProfile;
+pub use error::{CheckinError, DecryptError, Error, LoginError, RegisterError};
+pub use gcm::{
+    Connection, FirebaseConfig, FirebaseInstallation, GcmSession, GcmToken, RetiredKeys,
+};
+pub use gcm_device::GcmDevice;
+pub use payload::{FcmPayload, Notification, Priority};
+pub use proxy::{Proxy, ProxyKind};
+pub use push::{
+    encode_stanza, new_heartbeat_ack, ContentEncoding, DataMessage, EndReason, HeartbeatOptions, Message,
+    MessageStream, MessageTag, OutgoingStanza, DEFAULT_HEARTBEAT_ACK_TIMEOUT,
+    DEFAULT_HEARTBEAT_INTERVAL,
+};
+pub use retry::RetryPolicy;
+pub use store::{FileIdStore, MemoryIdStore, PersistentIdStore};
 
 use serde::{Deserialize, Serialize};
 
@@ -70,8 +105,11 @@ pub struct FcmCredentials {
     pub target_sdk: Option<i32>,
 }
 
+/// Firebase Installations auth tokens closer than this to expiry are refreshed before use
+const FIS_TOKEN_MARGIN: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
 /// A registered FCM client that can receive messages
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Registration {
     /// GCM session with android_id and security_token
     pub gcm_session: GcmSession,
@@ -79,34 +117,64 @@ pub struct Registration {
     pub gcm_token: GcmToken,
     /// The credentials used for registration
     pub credentials: FcmCredentials,
+    /// Firebase Installation the token was registered with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firebase_installation: Option<FirebaseInstallation>,
+    /// Topics the token is subscribed to, without the `/topics/` prefix
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub topics: Vec<String>,
+}
+
+impl FcmCredentials {
+    fn firebase_config(&self) -> FirebaseConfig {
+        FirebaseConfig {
+            project_id: self.project_id.clone(),
+            api_key: self.api_key.clone(),
+            app_id: self.app_id.clone(),
+        }
+    }
 }
 
 impl Registration {
     /// Register with FCM and get a token
-    pub async fn register(http: &reqwest::Client, creds: &FcmCredentials) -> Result<Self, Error> {
+    pub async fn register(
+        http: &reqwest::Client,
+        config: &ClientConfig,
+        creds: &FcmCredentials,
+    ) -> Result<Self, Error> {
         // Step 1: GCM checkin to get android_id and security_token
         tracing::debug!("Performing GCM checkin...");
-        let gcm_session = GcmSession::checkin(http).await?;
+        let gcm_session = GcmSession::checkin(http, config).await?;
         tracing::info!(
             "GCM checkin complete: android_id={}",
             gcm_session.android_id
         );
 
         // Small delay between checkin and registration (microG has implicit delay)
-        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        tokio::time::sleep(config.checkin_delay).await;
 
+        Self::register_with_session(http, config, gcm_session, creds).await
+    }
+
+    /// Register with FCM under an existing checkin
+    ///
+    /// `gcm_session` should carry keys of its own, see [`GcmSession::with_new_keys`]
+    /// for registering another app on the same device.
+    pub async fn register_with_session(
+        http: &reqwest::Client,
+        config: &ClientConfig,
+        gcm_session: GcmSession,
+        creds: &FcmCredentials,
+    ) -> Result<Self, Error> {
         // Step 2: Register with Firebase Installations to get FID and auth token
         // This is required for modern Firebase SDK (>= 20.1.1)
-        let firebase_config = FirebaseConfig {
-            project_id: creds.project_id.clone(),
-            api_key: creds.api_key.clone(),
-            app_id: creds.app_id.clone(),
-        };
+        let firebase_config = creds.firebase_config();
 
         let cert_sha1 = creds.cert_sha1.as_deref().unwrap_or("");
         tracing::debug!("Registering with Firebase Installations...");
         let firebase_installation = GcmSession::register_firebase_installation(
             http,
+            config,
             &firebase_config,
             &creds.package_name,
             cert_sha1,
@@ -119,29 +187,126 @@ impl Registration {
 
         // Step 3: Register with GCM to get a token (has built-in retry for transient errors)
         tracing::debug!("Registering with GCM...");
+        let gcm_token =
+            Self::register_token(http, config, &gcm_session, creds, &firebase_installation).await?;
+
+        Ok(Self {
+            gcm_session,
+            gcm_token,
+            credentials: creds.clone(),
+            firebase_installation: Some(firebase_installation),
+            topics: Vec::new(),
+        })
+    }
+
+    async fn register_token(
+        http: &reqwest::Client,
+        config: &ClientConfig,
+        gcm_session: &GcmSession,
+        creds: &FcmCredentials,
+        firebase_installation: &FirebaseInstallation,
+    ) -> Result<GcmToken, Error> {
         let gcm_token = gcm_session
             .register(
                 http,
+                config,
                 &creds.sender_id,
                 &creds.package_name,
                 creds.cert_sha1.as_deref(),
                 creds.app_version,
                 creds.app_version_name.as_deref(),
                 creds.target_sdk,
-                Some(&firebase_config),
-                Some(&firebase_installation),
+                Some(&creds.firebase_config()),
+                Some(firebase_installation),
             )
             .await?;
         tracing::info!(
             "GCM registration complete: token={}...",
             &gcm_token.token[..20.min(gcm_token.token.len())]
         );
+        Ok(gcm_token)
+    }
 
-        Ok(Self {
-            gcm_session,
-            gcm_token,
-            credentials: creds.clone(),
-        })
+    /// Get a new Firebase Installations auth token using the stored refresh token
+    ///
+    /// Registrations saved before the installation was kept have nothing to
+    /// refresh, so they get a new installation (and with it a new FID).
+    pub async fn refresh_installation(
+        &mut self,
+        http: &reqwest::Client,
+        config: &ClientConfig,
+    ) -> Result<&FirebaseInstallation, Error> {
+        let creds = &self.credentials;
+        let firebase_config = creds.firebase_config();
+        let cert_sha1 = creds.cert_sha1.as_deref().unwrap_or("");
+        let installation = match &self.firebase_installation {
+            Some(installation) => {
+                GcmSession::generate_firebase_auth_token(
+                    http,
+                    config,
+                    &firebase_config,
+                    &creds.package_name,
+                    cert_sha1,
+                    installation,
+                )
+                .await?
+            }
+            None => {
+                GcmSession::register_firebase_installation(
+                    http,
+                    config,
+                    &firebase_config,
+                    &creds.package_name,
+                    cert_sha1,
+                )
+                .await?
+            }
+        };
+        Ok(self.firebase_installation.insert(installation))
+    }
+
+    /// Renew the FCM token in place
+    ///
+    /// Refreshes the Firebase Installations auth token and registers with GCM
+    /// again under the same checkin and FID, so the app server normally keeps
+    /// seeing the same FCM token.
+    ///
+    /// Registrations without a [`Registration::firebase_installation`] have no
+    /// FID to keep: they get a new installation and therefore a new FCM token,
+    /// which has to be passed on to the app server. Leave them alone if the
+    /// current token still works.
+    pub async fn refresh_token(
+        &mut self,
+        http: &reqwest::Client,
+        config: &ClientConfig,
+    ) -> Result<(), Error> {
+        let installation = self.refresh_installation(http, config).await?.clone();
+        self.gcm_token = Self::register_token(
+            http,
+            config,
+            &self.gcm_session,
+            &self.credentials,
+            &installation,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// The Firebase installation with an auth token that is good for a while
+    ///
+    /// Refreshes the auth token when it is about to expire, and creates an
+    /// installation for registrations that have none.
+    async fn usable_installation(
+        &mut self,
+        http: &reqwest::Client,
+        config: &ClientConfig,
+    ) -> Result<FirebaseInstallation, Error> {
+        match &self.firebase_installation {
+            Some(installation) if !installation.auth_token_expires_within(FIS_TOKEN_MARGIN) => {
+                Ok(installation.clone())
+            }
+            _ => Ok(self.refresh_installation(http, config).await?.clone()),
+        }
     }
 
     /// Get the FCM token that can be used to receive messages
@@ -150,17 +315,185 @@ impl Registration {
     }
 
     /// Refresh the GCM session (checkin again)
-    pub async fn refresh_session(&mut self, http: &reqwest::Client) -> Result<(), Error> {
-        self.gcm_session = self.gcm_session.refresh(http).await?;
+    pub async fn refresh_session(
+        &mut self,
+        http: &reqwest::Client,
+        config: &ClientConfig,
+    ) -> Result<(), Error> {
+        self.gcm_session = self.gcm_session.refresh(http, config).await?;
+        Ok(())
+    }
+
+    /// Rotate the push encryption keys and register them with GCM
+    ///
+    /// Generates a new key pair and auth secret and registers again under the
+    /// same checkin and FID, so senders receive the new `encryption_key` and
+    /// `encryption_auth`. The old keys still decrypt for `grace`, covering
+    /// messages that were encrypted before the sender saw the new keys. The
+    /// registration is left unchanged if registering fails.
+    pub async fn rotate_keys(
+        &mut self,
+        http: &reqwest::Client,
+        config: &ClientConfig,
+        grace: std::time::Duration,
+    ) -> Result<(), Error> {
+        let installation = self.usable_installation(http, config).await?;
+
+        let mut gcm_session = self.gcm_session.clone();
+        gcm_session.drop_expired_keys();
+        gcm_session.rotate_keys(grace)?;
+
+        self.gcm_token =
+            Self::register_token(http, config, &gcm_session, &self.credentials, &installation)
+                .await?;
+        self.gcm_session = gcm_session;
+        tracing::info!("Rotated FCM encryption keys for {}", self.credentials.package_name);
+        Ok(())
+    }
+
+    /// Subscribe the token to an FCM topic
+    ///
+    /// Messages sent to the topic then arrive on the stream with
+    /// [`DataMessage::topic`] set. The topic is remembered in
+    /// [`Registration::topics`] so it can be replayed later.
+    pub async fn subscribe_topic(
+        &mut self,
+        http: &reqwest::Client,
+        config: &ClientConfig,
+        topic: &str,
+    ) -> Result<(), Error> {
+        self.update_topic_subscription(http, config, topic, true)
+            .await
+    }
+
+    /// Unsubscribe the token from an FCM topic
+    pub async fn unsubscribe_topic(
+        &mut self,
+        http: &reqwest::Client,
+        config: &ClientConfig,
+        topic: &str,
+    ) -> Result<(), Error> {
+        self.update_topic_subscription(http, config, topic, false)
+            .await
+    }
+
+    async fn update_topic_subscription(
+        &mut self,
+        http: &reqwest::Client,
+        config: &ClientConfig,
+        topic: &str,
+        subscribe: bool,
+    ) -> Result<(), Error> {
+        // Topic requests need an installation, older saved registrations get a new one
+        let installation = self.usable_installation(http, config).await?;
+        let creds = &self.credentials;
+        self.gcm_session
+            .update_topic_subscription(
+                http,
+                config,
+                &self.gcm_token.token,
+                topic,
+                subscribe,
+                &creds.package_name,
+                creds.cert_sha1.as_deref(),
+                creds.app_version,
+                creds.app_version_name.as_deref(),
+                &creds.firebase_config(),
+                &installation,
+            )
+            .await?;
+
+        let name = topic.strip_prefix(gcm::TOPIC_PREFIX).unwrap_or(topic);
+        self.topics.retain(|t| t != name);
+        if subscribe {
+            self.topics.push(name.to_string());
+        }
+        Ok(())
+    }
+
+    /// Delete the FCM token and the Firebase Installation behind it
+    ///
+    /// After this the token no longer receives messages and app servers get
+    /// `NotRegistered` when sending to it. The GCM registration is deleted
+    /// first, so a failure there leaves the installation intact for a retry.
+    /// An auth token that is about to expire is refreshed beforehand, GCM
+    /// rejects the deletion otherwise.
+    pub async fn unregister(
+        &mut self,
+        http: &reqwest::Client,
+        config: &ClientConfig,
+    ) -> Result<(), Error> {
+        if self
+            .firebase_installation
+            .as_ref()
+            .is_some_and(|installation| installation.auth_token_expires_within(FIS_TOKEN_MARGIN))
+        {
+            self.refresh_installation(http, config).await?;
+        }
+
+        let creds = &self.credentials;
+        self.gcm_session
+            .unregister(
+                http,
+                config,
+                &creds.sender_id,
+                &creds.package_name,
+                creds.cert_sha1.as_deref(),
+                self.firebase_installation.as_ref(),
+            )
+            .await?;
+        tracing::info!("GCM unregistration complete for {}", creds.package_name);
+
+        if let Some(installation) = &self.firebase_installation {
+            GcmSession::delete_firebase_installation(
+                http,
+                config,
+                &creds.firebase_config(),
+                &creds.package_name,
+                creds.cert_sha1.as_deref().unwrap_or(""),
+                installation,
+            )
+            .await?;
+        }
         Ok(())
     }
 
     /// Connect to mtalk.google.com and return a message stream
+    ///
+    /// The stream answers server pings and sends its own at the interval the
+    /// server asked for, ending with [`Error::HeartbeatTimeout`] on a dead socket.
     pub async fn connect(
         &self,
+        config: &ClientConfig,
         persistent_ids: Vec<String>,
     ) -> Result<MessageStream<tokio_rustls::client::TlsStream<tokio::net::TcpStream>>, Error> {
-        let connection = self.gcm_session.connect(persistent_ids).await?;
-        Ok(MessageStream::new(connection.0))
+        let connection = self.gcm_session.connect(config, persistent_ids).await?;
+        Ok(MessageStream::from_connection(
+            connection,
+            HeartbeatOptions::default(),
+        ))
+    }
+
+    /// Like [`Registration::connect`], with the persistent IDs kept in `store`
+    ///
+    /// The stored IDs are sent in the login and forgotten once it succeeds,
+    /// the stream records new ones as they arrive. Flushing the store is left
+    /// to the caller, see [`PersistentIdStore`].
+    pub async fn connect_with_store(
+        &self,
+        config: &ClientConfig,
+        store: std::sync::Arc<dyn PersistentIdStore>,
+    ) -> Result<MessageStream<tokio_rustls::client::TlsStream<tokio::net::TcpStream>>, Error> {
+        let persistent_ids = store.load()?;
+        let connection = self
+            .gcm_session
+            .connect(config, persistent_ids.clone())
+            .await?;
+        // IDs sent in the login are acknowledged by it
+        store.remove(&persistent_ids)?;
+        Ok(
+            MessageStream::from_connection(connection, HeartbeatOptions::default())
+                .with_id_store(store),
+        )
     }
 }
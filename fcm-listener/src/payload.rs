@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+
+/// Prefixes of the app_data keys FCM reserves for itself
+const RESERVED_PREFIXES: [&str; 2] = ["google.", "gcm."];
+
+/// Reserved app_data keys without one of the [`RESERVED_PREFIXES`]
+const RESERVED_KEYS: [&str; 4] = ["from", "message_type", "collapse_key", "message_id"];
+
+/// Prefixes of notification keys, the long form is sent by older servers
+const NOTIFICATION_PREFIXES: [&str; 2] = ["gcm.n.", "gcm.notification."];
+
+/// Delivery priority requested by the sender
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Normal,
+}
+
+impl Priority {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "high" | "10" => Some(Self::High),
+            "normal" | "5" => Some(Self::Normal),
+            _ => None,
+        }
+    }
+}
+
+/// Notification part of an FCM message, from the `gcm.n.*` keys
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Notification {
+    pub title: Option<String>,
+    pub body: Option<String>,
+    /// Android notification channel the sender asked for
+    pub channel_id: Option<String>,
+    /// Intent action to launch when the notification is tapped
+    pub click_action: Option<String>,
+    pub icon: Option<String>,
+    pub image: Option<String>,
+    /// Notifications with the same tag replace each other
+    pub tag: Option<String>,
+    pub color: Option<String>,
+    pub sound: Option<String>,
+    /// Deep link to open when the notification is tapped
+    pub link: Option<String>,
+}
+
+/// Typed view of a [`DataMessage`](crate::DataMessage)'s app_data
+///
+/// Splits the keys FCM reserves for itself (`google.*`, `gcm.*`, `gcm.n.*` and a
+/// few unprefixed ones) from the data the app server sent, and parses the
+/// well-known reserved keys the way the Firebase SDK does.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FcmPayload {
+    /// Message ID assigned by FCM, `google.message_id`
+    pub message_id: Option<String>,
+    /// When the app server sent the message, in milliseconds since the Unix epoch
+    pub sent_time: Option<i64>,
+    /// Time to live requested by the sender, in seconds
+    pub ttl: Option<i64>,
+    /// Messages with the same collapse key replace each other while queued
+    pub collapse_key: Option<String>,
+    /// Priority the sender asked for
+    pub priority: Option<Priority>,
+    /// Priority FCM delivered the message with, lower than requested when deprioritized
+    pub delivered_priority: Option<Priority>,
+    /// Present when the message carries a notification
+    pub notification: Option<Notification>,
+    /// The app server's own key-value pairs
+    pub data: BTreeMap<String, String>,
+    /// Every reserved entry, including the ones parsed into the fields above
+    pub reserved: BTreeMap<String, String>,
+}
+
+impl FcmPayload {
+    /// Split app_data entries into reserved and app keys
+    pub fn from_app_data<'a>(app_data: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let mut payload = Self::default();
+        for (key, value) in app_data {
+            let entries = if Self::is_reserved_key(key) {
+                &mut payload.reserved
+            } else {
+                &mut payload.data
+            };
+            entries.insert(key.to_string(), value.to_string());
+        }
+
+        let reserved = &payload.reserved;
+        let get = |key: &str| reserved.get(key).cloned();
+        payload.message_id = get("google.message_id").or_else(|| get("message_id"));
+        payload.sent_time = get("google.sent_time").and_then(|time| time.parse().ok());
+        payload.ttl = get("google.ttl").and_then(|ttl| ttl.parse().ok());
+        payload.collapse_key = get("collapse_key");
+        payload.priority = get("google.original_priority")
+            .or_else(|| get("google.priority"))
+            .and_then(|priority| Priority::parse(&priority));
+        payload.delivered_priority = get("google.delivered_priority")
+            .or_else(|| get("google.priority"))
+            .and_then(|priority| Priority::parse(&priority));
+        payload.notification = payload.parse_notification();
+        payload
+    }
+
+    /// Whether `key` is reserved by FCM rather than set by the app server
+    pub fn is_reserved_key(key: &str) -> bool {
+        RESERVED_PREFIXES
+            .iter()
+            .any(|prefix| key.starts_with(prefix))
+            || RESERVED_KEYS.contains(&key)
+    }
+
+    /// Value of a `gcm.n.*` key, e.g. `notification_value("title")`
+    pub fn notification_value(&self, name: &str) -> Option<&str> {
+        NOTIFICATION_PREFIXES.iter().find_map(|prefix| {
+            self.reserved
+                .get(&format!("{prefix}{name}"))
+                .map(String::as_str)
+        })
+    }
+
+    fn parse_notification(&self) -> Option<Notification> {
+        let has_notification = self.reserved.keys().any(|key| {
+            NOTIFICATION_PREFIXES
+                .iter()
+                .any(|prefix| key.starts_with(prefix))
+        });
+        if !has_notification {
+            return None;
+        }
+
+        let get = |name: &str| self.notification_value(name).map(String::from);
+        Some(Notification {
+            title: get("title"),
+            body: get("body"),
+            channel_id: get("android_channel_id"),
+            click_action: get("click_action"),
+            icon: get("icon"),
+            image: get("image"),
+            tag: get("tag"),
+            color: get("color"),
+            sound: get("sound2").or_else(|| get("sound")),
+            link: get("link_android").or_else(|| get("link")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_notification_keys_from_data() {
+        let payload = FcmPayload::from_app_data([
+            ("gcm.notification.title", "Long form title"),
+            ("gcm.notification.body", "Long form body"),
+            ("gcm.n.title", "Short form title"),
+            ("gcm.n.android_channel_id", "alerts"),
+            ("google.message_id", "0:123%abc"),
+            ("google.sent_time", "1700000000000"),
+            ("google.original_priority", "high"),
+            ("google.delivered_priority", "normal"),
+            ("collapse_key", "updates"),
+            ("from", "123456"),
+            ("title", "app's own title"),
+            ("notification.count", "3"),
+        ]);
+
+        let data: Vec<_> = payload.data.keys().map(String::as_str).collect();
+        assert_eq!(data, ["notification.count", "title"]);
+        assert!(payload.reserved.contains_key("gcm.notification.body"));
+        assert!(payload.reserved.contains_key("from"));
+        assert_eq!(payload.reserved.len(), 10);
+
+        // the short form wins when a key comes in both
+        let notification = payload.notification.unwrap();
+        assert_eq!(notification.title.as_deref(), Some("Short form title"));
+        assert_eq!(notification.body.as_deref(), Some("Long form body"));
+        assert_eq!(notification.channel_id.as_deref(), Some("alerts"));
+
+        assert_eq!(payload.message_id.as_deref(), Some("0:123%abc"));
+        assert_eq!(payload.sent_time, Some(1_700_000_000_000));
+        assert_eq!(payload.collapse_key.as_deref(), Some("updates"));
+        assert_eq!(payload.priority, Some(Priority::High));
+        assert_eq!(payload.delivered_priority, Some(Priority::Normal));
+    }
+
+    #[test]
+    fn data_only_message_has_no_notification() {
+        let payload = FcmPayload::from_app_data([
+            ("google.priority", "10"),
+            ("encrypted", "payload"),
+            ("gcm.message_type", "data"),
+        ]);
+        assert_eq!(payload.notification, None);
+        assert_eq!(payload.priority, Some(Priority::High));
+        assert_eq!(payload.delivered_priority, Some(Priority::High));
+        assert_eq!(
+            payload.data,
+            BTreeMap::from([("encrypted".to_string(), "payload".to_string())])
+        );
+    }
+}
@@ -3,9 +3,11 @@ use bytes::{Bytes, BytesMut};
 use pin_project_lite::pin_project;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[allow(dead_code)]
 #[derive(PartialEq, Debug)]
+#[repr(u8)]
 pub enum MessageTag {
     HeartbeatPing = 0,
     HeartbeatAck,
@@ -31,20 +33,74 @@ impl TryFrom<u8> for MessageTag {
 
     fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
         if value < Self::NumProtoTypes as u8 {
-            Ok(unsafe { std::mem::transmute(value) })
+            Ok(unsafe { std::mem::transmute::<u8, MessageTag>(value) })
         } else {
             Err(value)
         }
     }
 }
 
+/// A stanza received from the MCS server
 pub enum Message {
-    HeartbeatPing,
+    HeartbeatPing(crate::mcs::HeartbeatPing),
+    HeartbeatAck(crate::mcs::HeartbeatAck),
+    LoginRequest(crate::mcs::LoginRequest),
+    LoginResponse(crate::mcs::LoginResponse),
+    /// Server is closing the connection, this is the last item of the stream
+    Close,
+    Iq(crate::mcs::IqStanza),
     Data(DataMessage),
+    /// Server aborted the stream, this is the last item of the stream
+    StreamError(crate::mcs::StreamErrorStanza),
+    /// Stanza without a protobuf definition (presence, bind account, ...)
     Other(u8, Bytes),
 }
 
+/// A stanza to send to the MCS server through [`MessageStream`]'s `Sink` implementation
+///
+/// `last_stream_id_received` is filled in by the stream, whatever the stanza carries is
+/// overwritten.
+pub enum OutgoingStanza {
+    HeartbeatPing,
+    HeartbeatAck,
+    /// SelectiveAck IQ for received persistent IDs
+    SelectiveAck(Vec<String>),
+    /// StreamAck IQ confirming everything received so far
+    StreamAck,
+    Iq(crate::mcs::IqStanza),
+    /// Upstream data message
+    Data(crate::mcs::DataMessageStanza),
+    /// Hang up, nothing should be sent afterwards
+    Close,
+}
+
+/// Why a [`MessageStream`] stopped yielding messages
+#[derive(Clone, Debug)]
+pub enum EndReason {
+    /// Server sent a Close stanza
+    Close,
+    /// We sent a Close stanza through the [`futures_sink::Sink`]
+    LocalClose,
+    /// Server sent a StreamErrorStanza
+    StreamError(crate::mcs::StreamErrorStanza),
+    /// Connection was closed without a Close stanza
+    Disconnected,
+    /// Stream ended after yielding an error
+    Failed,
+}
+
+/// Web Push content encoding of an encrypted [`DataMessage`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentEncoding {
+    /// RFC 8291, salt and sender key are part of the payload
+    Aes128Gcm,
+    /// Legacy draft scheme, salt and sender key are in the `encryption` and
+    /// `crypto-key` app_data entries
+    AesGcm,
+}
+
 /// A data message received from FCM
+#[derive(Clone, Debug, Default)]
 pub struct DataMessage {
     /// Raw message data (typically JSON for FCM)
     pub raw_data: Option<Vec<u8>>,
@@ -56,15 +112,30 @@ pub struct DataMessage {
     pub from: Option<String>,
     /// Category/package name
     pub category: Option<String>,
+    /// Message ID set by the sender
+    pub id: Option<String>,
+    /// Registration the message was addressed to
+    pub to: Option<String>,
+    /// Collapse key
+    pub token: Option<String>,
+    /// Time to live in seconds, 0 for messages that are dropped rather than queued
+    pub ttl: Option<i32>,
+    /// When the message was sent, in seconds since the Unix epoch
+    pub sent: Option<i64>,
+    /// How long the server held the message before delivering it, in seconds
+    pub queued: Option<i32>,
+    /// When the message was read off the connection
+    pub received_at: Option<SystemTime>,
+    /// Registration ID of the receiving app
+    pub reg_id: Option<String>,
+    /// Server asks for the message to be acked right away
+    pub immediate_ack: Option<bool>,
+    /// Message came from Google's own servers rather than an app server
+    pub from_trusted_server: Option<bool>,
 }
 
-impl DataMessage {
-    fn decode(bytes: &[u8]) -> Result<Self, Error> {
-        use prost::Message;
-
-        let message = crate::mcs::DataMessageStanza::decode(bytes)
-            .map_err(|e| Error::ProtobufDecode("FCM data message", e))?;
-
+impl From<crate::mcs::DataMessageStanza> for DataMessage {
+    fn from(message: crate::mcs::DataMessageStanza) -> Self {
         // Extract app_data as key-value pairs
         let app_data: Vec<(String, String)> = message
             .app_data
@@ -72,15 +143,27 @@ impl DataMessage {
             .map(|field| (field.key, field.value))
             .collect();
 
-        Ok(Self {
+        Self {
             raw_data: message.raw_data,
             persistent_id: message.persistent_id,
             app_data,
             from: if message.from.is_empty() { None } else { Some(message.from) },
             category: if message.category.is_empty() { None } else { Some(message.category) },
-        })
+            id: message.id,
+            to: message.to,
+            token: message.token,
+            ttl: message.ttl,
+            sent: message.sent,
+            queued: message.queued,
+            received_at: Some(SystemTime::now()),
+            reg_id: message.reg_id,
+            immediate_ack: message.immediate_ack,
+            from_trusted_server: message.from_trusted_server,
+        }
     }
+}
 
+impl DataMessage {
     /// Get the message payload as bytes (if present)
     pub fn payload(&self) -> Option<&[u8]> {
         self.raw_data.as_deref()
@@ -93,6 +176,85 @@ impl DataMessage {
             .and_then(|data| std::str::from_utf8(data).ok())
     }
 
+    /// Topic the message was sent to, without the `/topics/` prefix
+    pub fn topic(&self) -> Option<&str> {
+        self.from
+            .as_deref()
+            .and_then(|from| from.strip_prefix(crate::gcm::TOPIC_PREFIX))
+    }
+
+    /// How the payload is encrypted, `None` for plaintext messages
+    pub fn content_encoding(&self) -> Option<ContentEncoding> {
+        match self.get_app_data("content-encoding") {
+            Some(encoding) if encoding.eq_ignore_ascii_case("aesgcm") => {
+                return Some(ContentEncoding::AesGcm)
+            }
+            Some(encoding) if encoding.eq_ignore_ascii_case("aes128gcm") => {
+                return Some(ContentEncoding::Aes128Gcm)
+            }
+            _ => {}
+        }
+        if self.get_app_data("crypto-key").is_some() && self.get_app_data("encryption").is_some() {
+            Some(ContentEncoding::AesGcm)
+        } else if self.get_app_data("encrypted").is_some() {
+            Some(ContentEncoding::Aes128Gcm)
+        } else {
+            None
+        }
+    }
+
+    /// How long the message waited between being sent and being received
+    ///
+    /// Uses the server's `queued` count when present, which is not affected by
+    /// clock differences, and the local clock against `sent` otherwise.
+    pub fn queued_duration(&self) -> Option<Duration> {
+        if let Some(queued) = self.queued {
+            return Some(Duration::from_secs(queued.max(0) as u64));
+        }
+        let sent = UNIX_EPOCH + Duration::from_secs(u64::try_from(self.sent?).ok()?);
+        self.received_at?.duration_since(sent).ok()
+    }
+
+    /// When the message stops being worth delivering, `None` if it does not expire
+    ///
+    /// Messages without a positive `ttl` were sent to be delivered right away or
+    /// not at all, so they never count as expired.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        let ttl = Duration::from_secs(u64::try_from(self.ttl?).ok().filter(|ttl| *ttl > 0)?);
+        match (self.received_at, self.queued) {
+            (Some(received_at), Some(_)) => {
+                let waited = self.queued_duration()?;
+                Some(received_at.checked_sub(waited)? + ttl)
+            }
+            _ => Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(self.sent?).ok()?) + ttl),
+        }
+    }
+
+    /// Whether the message outlived its `ttl` before it arrived
+    pub fn is_expired(&self) -> bool {
+        self.expired_at(self.received_at.unwrap_or_else(SystemTime::now))
+    }
+
+    /// Whether the message has expired by `time`
+    pub fn expired_at(&self, time: SystemTime) -> bool {
+        self.expires_at().is_some_and(|expires_at| expires_at <= time)
+    }
+
+    /// Reserved FCM keys and the app's own data, parsed from app_data
+    ///
+    /// The collapse key falls back to the stanza's `token` when app_data has none.
+    pub fn fcm_payload(&self) -> crate::FcmPayload {
+        let mut payload = crate::FcmPayload::from_app_data(
+            self.app_data
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        );
+        if payload.collapse_key.is_none() {
+            payload.collapse_key = self.token.clone();
+        }
+        payload
+    }
+
     /// Get an app_data value by key
     pub fn get_app_data(&self, key: &str) -> Option<&str> {
         self.app_data
@@ -102,12 +264,75 @@ impl DataMessage {
     }
 }
 
+/// Interval used for client pings when the server does not request one
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How long to wait for the server to answer a client ping
+pub const DEFAULT_HEARTBEAT_ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Client heartbeat settings for a [`MessageStream`]
+#[derive(Clone, Copy, Debug)]
+pub struct HeartbeatOptions {
+    /// Time between client pings, `None` uses the interval from the LoginResponse
+    pub interval: Option<Duration>,
+    /// Time allowed for the HeartbeatAck before the connection is considered dead
+    pub ack_timeout: Duration,
+}
+
+impl Default for HeartbeatOptions {
+    fn default() -> Self {
+        Self {
+            interval: None,
+            ack_timeout: DEFAULT_HEARTBEAT_ACK_TIMEOUT,
+        }
+    }
+}
+
+struct Heartbeat {
+    interval: Duration,
+    ack_timeout: Duration,
+    timer: Pin<Box<tokio::time::Sleep>>,
+    awaiting_ack: bool,
+}
+
+impl Heartbeat {
+    fn new(interval: Duration, ack_timeout: Duration) -> Self {
+        Self {
+            interval,
+            ack_timeout,
+            timer: Box::pin(tokio::time::sleep(interval)),
+            awaiting_ack: false,
+        }
+    }
+
+    fn acknowledged(&mut self) {
+        self.awaiting_ack = false;
+        self.timer
+            .as_mut()
+            .reset(tokio::time::Instant::now() + self.interval);
+    }
+}
+
 pin_project! {
     pub struct MessageStream<T> {
         #[pin]
         inner: T,
         bytes_required: usize,
         receive_buffer: BytesMut,
+        send_buffer: BytesMut,
+        needs_flush: bool,
+        heartbeat: Option<Heartbeat>,
+        auto_ack: bool,
+        drop_expired: bool,
+        stream_id_in: i32,
+        stream_id_out: i32,
+        server_last_stream_id_received: i32,
+        received_since_ack: usize,
+        unreported_ids: Vec<String>,
+        reported_ids: std::collections::VecDeque<(i32, Vec<String>)>,
+        confirmed_ids: Vec<String>,
+        id_store: Option<std::sync::Arc<dyn crate::PersistentIdStore>>,
+        end_reason: Option<EndReason>,
     }
 }
 
@@ -117,9 +342,111 @@ impl<T> MessageStream<T> {
             inner,
             bytes_required: 2,
             receive_buffer: BytesMut::with_capacity(1024),
+            send_buffer: BytesMut::new(),
+            needs_flush: false,
+            heartbeat: None,
+            auto_ack: true,
+            drop_expired: false,
+            stream_id_in: 0,
+            stream_id_out: 0,
+            server_last_stream_id_received: 0,
+            received_since_ack: 0,
+            unreported_ids: Vec::new(),
+            reported_ids: std::collections::VecDeque::new(),
+            confirmed_ids: Vec::new(),
+            id_store: None,
+            end_reason: None,
+        }
+    }
+
+    /// Continue the stream ID counters of a connection that has already exchanged stanzas
+    ///
+    /// A fresh login counts as one stanza each way, see [`MessageStream::from_connection`].
+    pub fn with_stream_ids(mut self, incoming: i32, outgoing: i32) -> Self {
+        self.stream_id_in = incoming;
+        self.stream_id_out = outgoing;
+        self
+    }
+
+    /// Number of stanzas received, sent as `last_stream_id_received` on every outgoing stanza
+    pub fn incoming_stream_id(&self) -> i32 {
+        self.stream_id_in
+    }
+
+    /// Number of stanzas sent
+    pub fn outgoing_stream_id(&self) -> i32 {
+        self.stream_id_out
+    }
+
+    /// Highest of our stanzas the server has reported receiving
+    pub fn server_last_stream_id_received(&self) -> i32 {
+        self.server_last_stream_id_received
+    }
+
+    /// Why the stream ended, `None` while it is still running
+    pub fn end_reason(&self) -> Option<&EndReason> {
+        self.end_reason.as_ref()
+    }
+
+    /// Take the persistent IDs whose acknowledgement the server has confirmed
+    ///
+    /// Once confirmed, the server will not redeliver these messages, so they no
+    /// longer need to be sent in the next `LoginRequest`.
+    pub fn take_confirmed_ids(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.confirmed_ids)
+    }
+
+    /// Record received persistent IDs in `store` and forget them once confirmed
+    ///
+    /// Messages whose ID is already in the store are acked again but not
+    /// yielded, so a message is handed out at most once even when the server
+    /// redelivers it to a restarted process. Store failures are logged and
+    /// otherwise ignored. The stream never calls [`PersistentIdStore::flush`](crate::PersistentIdStore::flush),
+    /// do that outside of polling, e.g. with `spawn_blocking` after each message.
+    pub fn with_id_store(mut self, store: std::sync::Arc<dyn crate::PersistentIdStore>) -> Self {
+        self.id_store = Some(store);
+        self
+    }
+
+    /// Send a HeartbeatPing every `interval` and fail the stream with
+    /// [`Error::HeartbeatTimeout`] if the server does not ack it within `ack_timeout`
+    pub fn with_heartbeat(mut self, interval: Duration, ack_timeout: Duration) -> Self {
+        self.heartbeat = Some(Heartbeat::new(interval, ack_timeout));
+        self
+    }
+
+    /// Whether received persistent IDs are acked on the live connection as they arrive
+    ///
+    /// Enabled by default. Disable it to ack with [`MessageStream::ack`] only once a
+    /// message has actually been processed.
+    pub fn with_auto_ack(mut self, enabled: bool) -> Self {
+        self.auto_ack = enabled;
+        self
+    }
+
+    /// Whether messages that outlived their TTL are dropped instead of yielded
+    ///
+    /// Disabled by default, check [`DataMessage::is_expired`] to handle them
+    /// yourself. Dropped messages are acked like any other.
+    pub fn with_drop_expired(mut self, enabled: bool) -> Self {
+        self.drop_expired = enabled;
+        self
+    }
+
+    /// Acknowledge received persistent IDs with a SelectiveAck IQ
+    ///
+    /// The IQ is written out the next time the stream is polled or flushed.
+    pub fn ack(&mut self, persistent_ids: Vec<String>) {
+        if !persistent_ids.is_empty() {
+            self.queue_outgoing(OutgoingStanza::SelectiveAck(persistent_ids));
         }
     }
 
+    /// Confirm everything received so far with a StreamAck IQ
+    pub fn stream_ack(&mut self) {
+        self.queue_outgoing(OutgoingStanza::StreamAck);
+    }
+
     /// returns a decoded protobuf varint or a state change if there is insufficient data
     fn try_read_varint<'a>(mut bytes: impl Iterator<Item = &'a u8>) -> (usize, usize) {
         let mut result = 0;
@@ -136,6 +463,11 @@ impl<T> MessageStream<T> {
             // Strip the continuation bit
             let value_part = byte & !0x80u8;
 
+            // MCS lengths fit in 5 bytes, anything longer is garbage that would overflow
+            if bytes_read == 5 {
+                return (usize::MAX, 2 + bytes_read);
+            }
+
             // accumulate little endian bits
             result += (value_part as usize) << (bytes_read * 7);
 
@@ -147,6 +479,286 @@ impl<T> MessageStream<T> {
             bytes_read += 1;
         }
     }
+
+    fn queue<S: Stanza>(&mut self, mut stanza: S) {
+        // every outgoing stanza tells the server how far we have read, which also
+        // reports every persistent ID received up to that point
+        stanza.set_last_stream_id_received(self.stream_id_in);
+        self.stream_id_out += 1;
+        self.received_since_ack = 0;
+        if !self.unreported_ids.is_empty() {
+            let ids = std::mem::take(&mut self.unreported_ids);
+            self.reported_ids.push_back((self.stream_id_out, ids));
+        }
+
+        encode_stanza(S::TAG, &stanza, &mut self.send_buffer);
+    }
+
+    fn queue_outgoing(&mut self, stanza: OutgoingStanza) {
+        use prost::Message;
+
+        match stanza {
+            OutgoingStanza::HeartbeatPing => self.queue(crate::mcs::HeartbeatPing::default()),
+            OutgoingStanza::HeartbeatAck => self.queue(crate::mcs::HeartbeatAck::default()),
+            OutgoingStanza::SelectiveAck(persistent_ids) => {
+                let selective_ack = crate::mcs::SelectiveAck {
+                    id: persistent_ids,
+                };
+                self.queue(new_ack_iq(
+                    SELECTIVE_ACK_EXTENSION,
+                    selective_ack.encode_to_vec(),
+                ));
+            }
+            OutgoingStanza::StreamAck => {
+                self.queue(new_ack_iq(STREAM_ACK_EXTENSION, Vec::new()));
+            }
+            OutgoingStanza::Iq(iq) => self.queue(iq),
+            OutgoingStanza::Data(data) => self.queue(data),
+            OutgoingStanza::Close => {
+                self.stream_id_out += 1;
+                encode_stanza(
+                    MessageTag::Close,
+                    &crate::mcs::Close::default(),
+                    &mut self.send_buffer,
+                );
+                // the Close stays queued until the sink is flushed or closed
+                self.end(EndReason::LocalClose);
+            }
+        }
+    }
+
+    /// decodes a stanza from the server, counting it even if it turns out malformed
+    fn decode_stanza<S: Stanza>(&mut self, bytes: &[u8], kind: &'static str) -> Result<S, Error> {
+        let stanza = decode::<S>(bytes, kind);
+        self.received(stanza.as_ref().ok().and_then(S::last_stream_id_received));
+        stanza
+    }
+
+    /// bookkeeping for every stanza received from the server
+    fn received(&mut self, last_stream_id_received: Option<i32>) {
+        self.stream_id_in += 1;
+        self.received_since_ack += 1;
+
+        let Some(last_stream_id_received) = last_stream_id_received else {
+            return;
+        };
+
+        self.server_last_stream_id_received = last_stream_id_received;
+        while let Some((stream_id, _)) = self.reported_ids.front() {
+            if *stream_id > last_stream_id_received {
+                break;
+            }
+            let (_, ids) = self.reported_ids.pop_front().expect("front was just checked");
+            if let Some(store) = &self.id_store {
+                if let Err(e) = store.remove(&ids) {
+                    tracing::warn!("Failed to forget confirmed persistent IDs: {}", e);
+                }
+            }
+            self.confirmed_ids.extend(ids);
+        }
+    }
+
+    /// queues a client ping when the interval elapses, fails if the previous one went unanswered
+    fn poll_heartbeat(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
+        use std::future::Future;
+
+        let Some(heartbeat) = self.heartbeat.as_mut() else {
+            return Ok(());
+        };
+
+        let mut send_ping = false;
+        while heartbeat.timer.as_mut().poll(cx).is_ready() {
+            if heartbeat.awaiting_ack {
+                return Err(Error::HeartbeatTimeout);
+            }
+
+            heartbeat.awaiting_ack = true;
+            let deadline = tokio::time::Instant::now() + heartbeat.ack_timeout;
+            heartbeat.timer.as_mut().reset(deadline);
+            send_ping = true;
+        }
+
+        if send_ping {
+            tracing::trace!("Sending MCS heartbeat ping");
+            self.queue(crate::mcs::HeartbeatPing::default());
+        }
+
+        Ok(())
+    }
+
+    /// decodes a complete frame, `None` if the stream consumed it itself
+    fn handle_frame(
+        &mut self,
+        tag: Result<MessageTag, u8>,
+        tag_value: u8,
+        bytes: BytesMut,
+    ) -> Result<Option<Message>, Error> {
+        let message = match tag {
+            Ok(MessageTag::DataMessageStanza) => {
+                let stanza: crate::mcs::DataMessageStanza =
+                    self.decode_stanza(&bytes, "FCM data message")?;
+                let message = DataMessage::from(stanza);
+                let mut redelivered = false;
+                if let Some(persistent_id) = &message.persistent_id {
+                    if let Some(store) = &self.id_store {
+                        match store.insert(persistent_id) {
+                            Ok(new) => redelivered = !new,
+                            Err(e) => tracing::warn!("Failed to record persistent ID: {}", e),
+                        }
+                    }
+                }
+                let expired = self.drop_expired && message.is_expired();
+
+                // messages the caller never sees have to be acked here
+                let dropped = redelivered || expired;
+                if let Some(persistent_id) = &message.persistent_id {
+                    self.unreported_ids.push(persistent_id.clone());
+                    if self.auto_ack || dropped {
+                        self.ack(vec![persistent_id.clone()]);
+                    }
+                }
+                if redelivered {
+                    tracing::debug!(
+                        "Dropping redelivered FCM message {:?}",
+                        message.persistent_id
+                    );
+                    None
+                } else if expired {
+                    tracing::debug!(
+                        "Dropping FCM message {:?}, expired after waiting {:?}",
+                        message.persistent_id,
+                        message.queued_duration()
+                    );
+                    None
+                } else {
+                    Some(Message::Data(message))
+                }
+            }
+            Ok(MessageTag::HeartbeatPing) => {
+                let ping = self.decode_stanza(&bytes, "MCS heartbeat ping")?;
+
+                // answer server pings right away, the ack goes out with the next
+                // write opportunity if the socket is not writable now
+                self.queue(crate::mcs::HeartbeatAck::default());
+                Some(Message::HeartbeatPing(ping))
+            }
+            Ok(MessageTag::HeartbeatAck) => {
+                let ack = self.decode_stanza(&bytes, "MCS heartbeat ack")?;
+
+                if let Some(heartbeat) = self.heartbeat.as_mut() {
+                    heartbeat.acknowledged();
+                }
+                Some(Message::HeartbeatAck(ack))
+            }
+            Ok(MessageTag::LoginRequest) => {
+                self.received(None);
+                Some(Message::LoginRequest(decode(&bytes, "MCS login request")?))
+            }
+            Ok(MessageTag::LoginResponse) => {
+                let response = self.decode_stanza(&bytes, "MCS login response")?;
+                Some(Message::LoginResponse(response))
+            }
+            Ok(MessageTag::StreamErrorStanza) => {
+                self.received(None);
+                // the server hangs up after a stream error, even one we cannot read
+                let stream_error: crate::mcs::StreamErrorStanza =
+                    match decode(&bytes, "MCS stream error") {
+                        Ok(stream_error) => stream_error,
+                        Err(e) => {
+                            self.end(EndReason::Failed);
+                            return Err(e);
+                        }
+                    };
+                tracing::debug!("MCS stream error: {:?}", stream_error);
+                self.end(EndReason::StreamError(stream_error.clone()));
+                Some(Message::StreamError(stream_error))
+            }
+            Ok(MessageTag::IqStanza) => {
+                let iq: crate::mcs::IqStanza = self.decode_stanza(&bytes, "MCS IQ stanza")?;
+
+                match iq.extension.as_ref().map(|extension| extension.id) {
+                    // the server confirming stanzas we sent, nothing for the caller to do
+                    Some(SELECTIVE_ACK_EXTENSION | STREAM_ACK_EXTENSION) => {
+                        tracing::trace!("Received MCS ack IQ from server");
+                        None
+                    }
+                    _ => Some(Message::Iq(iq)),
+                }
+            }
+            _ => {
+                self.received(None);
+                Some(Message::Other(tag_value, bytes.into()))
+            }
+        };
+
+        // tell the server how far we have read if nothing else has in a while
+        if self.received_since_ack >= UNACKED_STANZAS_BEFORE_STREAM_ACK {
+            self.stream_ack();
+        }
+
+        Ok(message)
+    }
+
+    /// terminates the stream, dropping anything left in the receive buffer
+    fn end(&mut self, reason: EndReason) {
+        self.bytes_required = 0;
+        self.receive_buffer.clear();
+        self.heartbeat = None;
+        self.end_reason = Some(reason);
+    }
+
+    /// terminates the stream with an error, nothing queued will be sent anymore
+    fn fail(&mut self, error: Error) -> Poll<Option<Result<Message, Error>>> {
+        self.end(EndReason::Failed);
+        self.send_buffer.clear();
+        Poll::Ready(Some(Err(error)))
+    }
+}
+
+impl<T> MessageStream<T>
+where
+    T: tokio::io::AsyncWrite + Unpin,
+{
+    /// writes out queued stanzas, `Pending` only means the socket is not writable yet
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        use bytes::Buf;
+
+        while !self.send_buffer.is_empty() {
+            let written = std::task::ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.send_buffer)
+            )?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.send_buffer.advance(written);
+            self.needs_flush = true;
+        }
+
+        if self.needs_flush {
+            std::task::ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+            self.needs_flush = false;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl MessageStream<tokio_rustls::client::TlsStream<tokio::net::TcpStream>> {
+    /// Wrap an established connection with a heartbeat driver
+    ///
+    /// Pings at `heartbeat.interval` if set, otherwise at the interval the server
+    /// requested in its LoginResponse, falling back to [`DEFAULT_HEARTBEAT_INTERVAL`].
+    pub fn from_connection(connection: crate::Connection, heartbeat: HeartbeatOptions) -> Self {
+        let interval = heartbeat
+            .interval
+            .or_else(|| connection.heartbeat_interval())
+            .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL);
+
+        // the LoginRequest and LoginResponse are the first stanza in each direction
+        Self::new(connection.stream)
+            .with_stream_ids(1, 1)
+            .with_heartbeat(interval, heartbeat.ack_timeout)
+    }
 }
 
 impl<T> tokio_stream::Stream for MessageStream<T>
@@ -160,73 +772,90 @@ where
         use std::future::Future;
         use tokio::io::AsyncReadExt;
 
+        let this = &mut *self;
+
+        if this.bytes_required == 0 && this.receive_buffer.is_empty() {
+            return Poll::Ready(None);
+        }
+
+        if let Err(e) = this.poll_heartbeat(cx) {
+            return this.fail(e);
+        }
+
+        if let Poll::Ready(Err(e)) = this.poll_send(cx) {
+            return this.fail(Error::Socket(e));
+        }
+
         loop {
-            let mut bytes = self.receive_buffer.iter();
+            let mut bytes = this.receive_buffer.iter();
             if let Some(tag_value) = bytes.next() {
                 let tag_value = *tag_value;
                 let tag = MessageTag::try_from(tag_value);
                 if matches!(tag, Ok(MessageTag::Close)) {
-                    self.bytes_required = 0;
-                    self.receive_buffer.clear();
-                    return Poll::Ready(None);
+                    // whatever follows the tag is irrelevant, the server is hanging up
+                    this.received(None);
+                    this.end(EndReason::Close);
+                    return Poll::Ready(Some(Ok(Message::Close)));
                 }
 
                 // determine size of the message
                 let (size, offset) = Self::try_read_varint(bytes);
+                if size > MAX_FRAME_SIZE {
+                    return this.fail(Error::Socket(frame_too_large(size)));
+                }
                 let bytes_required = offset + size;
-                if bytes_required <= self.receive_buffer.len() {
+                if bytes_required <= this.receive_buffer.len() {
                     // sizeof next_message is unknown, if sizeof next_message < sizeof this_message
                     // && we don't resetting expectations -> we block despite having received the
                     // smaller message in its entirety
-                    self.bytes_required = 2;
-
-                    self.receive_buffer.advance(offset);
-                    let bytes = self.receive_buffer.split_to(size);
-                    return Poll::Ready(Some(Ok(match tag {
-                        Ok(MessageTag::DataMessageStanza) => {
-                            match DataMessage::decode(&bytes) {
-                                Err(e) => return Poll::Ready(Some(Err(e))),
-                                Ok(m) => Message::Data(m),
-                            }
-                        }
-                        Ok(MessageTag::HeartbeatPing) => Message::HeartbeatPing,
-                        _ => Message::Other(tag_value, bytes.into()),
-                    })));
+                    this.bytes_required = 2;
+
+                    this.receive_buffer.advance(offset);
+                    let bytes = this.receive_buffer.split_to(size);
+                    let handled = this.handle_frame(tag, tag_value, bytes);
+
+                    // flush whatever the frame made us queue (acks) before handing it out
+                    if let Poll::Ready(Err(e)) = this.poll_send(cx) {
+                        return this.fail(Error::Socket(e));
+                    }
+
+                    match handled {
+                        Ok(Some(message)) => return Poll::Ready(Some(Ok(message))),
+                        // consumed by the stream itself, keep reading
+                        Ok(None) => continue,
+                        Err(e) => return Poll::Ready(Some(Err(e))),
+                    }
                 }
 
                 // ensure buffer can contain at least the current message
-                let capacity = self.receive_buffer.capacity();
+                let capacity = this.receive_buffer.capacity();
                 if bytes_required > capacity {
-                    self.receive_buffer.reserve(bytes_required - capacity);
+                    this.receive_buffer.reserve(bytes_required - capacity);
                 }
 
-                self.bytes_required = bytes_required;
-            } else if self.bytes_required == 0 {
+                this.bytes_required = bytes_required;
+            } else if this.bytes_required == 0 {
                 return Poll::Ready(None);
             }
 
             loop {
                 // insufficient data in the buffer, fill from inner
-                let mut that = self.as_mut().project();
-                let task = that.inner.read_buf(that.receive_buffer);
+                let task = this.inner.read_buf(&mut this.receive_buffer);
                 tokio::pin!(task);
                 match task.poll(cx) {
                     Poll::Pending => return Poll::Pending,
                     Poll::Ready(Err(e)) => {
                         // failfast
-                        self.bytes_required = 0;
-                        self.receive_buffer.clear();
-                        return Poll::Ready(Some(Err(Error::Socket(e))));
+                        return this.fail(Error::Socket(e));
                     }
                     Poll::Ready(Ok(0)) => {
                         // probably a broken pipe, which means whatever incomplete
                         // message we have buffered will just have to be chucked
-                        self.bytes_required = 0;
-                        self.receive_buffer.clear();
+                        this.end(EndReason::Disconnected);
                         return Poll::Ready(None);
                     }
                     _ => {
-                        if self.receive_buffer.len() >= self.bytes_required {
+                        if this.receive_buffer.len() >= this.bytes_required {
                             break;
                         }
                     }
@@ -236,6 +865,55 @@ where
     }
 }
 
+/// Bytes queued for sending before `poll_ready` starts pushing them out
+const SEND_BUFFER_HIGH_WATER: usize = 16 * 1024;
+
+/// Writes go through the same buffer as the acks and pings the stream sends by itself,
+/// so stream IDs stay consistent. Use `futures::StreamExt::split` to send from a different
+/// task than the one reading. Sending [`OutgoingStanza::Close`] ends the stream, later
+/// stanzas are refused.
+impl<T> futures_sink::Sink<OutgoingStanza> for MessageStream<T>
+where
+    T: tokio::io::AsyncWrite + Unpin,
+{
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = &mut *self;
+
+        if this.end_reason.is_some() {
+            return Poll::Ready(Err(Error::Socket(std::io::ErrorKind::NotConnected.into())));
+        }
+
+        if this.send_buffer.len() >= SEND_BUFFER_HIGH_WATER {
+            std::task::ready!(this.poll_send(cx)).map_err(Error::Socket)?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, stanza: OutgoingStanza) -> Result<(), Error> {
+        if self.end_reason.is_some() {
+            return Err(Error::Socket(std::io::ErrorKind::NotConnected.into()));
+        }
+        self.queue_outgoing(stanza);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.poll_send(cx).map_err(Error::Socket)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = &mut *self;
+
+        std::task::ready!(this.poll_send(cx)).map_err(Error::Socket)?;
+        Pin::new(&mut this.inner)
+            .poll_shutdown(cx)
+            .map_err(Error::Socket)
+    }
+}
+
 impl<T> std::ops::Deref for MessageStream<T> {
     type Target = T;
 
@@ -250,14 +928,235 @@ impl<T> std::ops::DerefMut for MessageStream<T> {
     }
 }
 
-pub fn new_heartbeat_ack() -> BytesMut {
+/// Encode a stanza as the `tag + varint length + payload` frame MCS expects
+pub fn encode_stanza<M: prost::Message>(tag: MessageTag, message: &M, dst: &mut BytesMut) {
     use bytes::BufMut;
 
-    let ack = crate::mcs::HeartbeatAck::default();
-    let mut bytes = BytesMut::with_capacity(prost::Message::encoded_len(&ack) + 5);
-    bytes.put_u8(MessageTag::HeartbeatAck as u8);
-    prost::Message::encode_length_delimited(&ack, &mut bytes)
-        .expect("heartbeat ack serialization should succeed");
+    dst.reserve(1 + prost::length_delimiter_len(message.encoded_len()) + message.encoded_len());
+    dst.put_u8(tag as u8);
+    message
+        .encode_length_delimited(dst)
+        .expect("stanza serialization should succeed");
+}
+
+/// Largest stanza accepted from the server, real ones stay far below this
+const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+fn frame_too_large(size: usize) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("MCS frame of {size} bytes exceeds the {MAX_FRAME_SIZE} byte limit"),
+    )
+}
+
+/// Read a single `tag + varint length + payload` frame from the connection
+pub(crate) async fn read_frame<R>(reader: &mut R) -> std::io::Result<(u8, BytesMut)>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+
+    let tag = reader.read_u8().await?;
+
+    let mut size = 0usize;
+    for shift in (0..35).step_by(7) {
+        let byte = reader.read_u8().await?;
+        size |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            if size > MAX_FRAME_SIZE {
+                return Err(frame_too_large(size));
+            }
+            let mut payload = BytesMut::zeroed(size);
+            reader.read_exact(&mut payload).await?;
+            return Ok((tag, payload));
+        }
+    }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "MCS frame length varint is too long",
+    ))
+}
+
+fn decode<M: prost::Message + Default>(bytes: &[u8], kind: &'static str) -> Result<M, Error> {
+    M::decode(bytes).map_err(|e| Error::ProtobufDecode(kind, e))
+}
+
+/// Stanzas received without sending anything back before a StreamAck is due
+const UNACKED_STANZAS_BEFORE_STREAM_ACK: usize = 10;
+
+/// Stanzas that carry `last_stream_id_received`
+trait Stanza: prost::Message + Default {
+    const TAG: MessageTag;
+
+    fn last_stream_id_received(&self) -> Option<i32>;
+
+    fn set_last_stream_id_received(&mut self, stream_id: i32);
+}
+
+macro_rules! impl_stanza {
+    ($($stanza:ident => $tag:ident),* $(,)?) => {
+        $(
+            impl Stanza for crate::mcs::$stanza {
+                const TAG: MessageTag = MessageTag::$tag;
+
+                fn last_stream_id_received(&self) -> Option<i32> {
+                    self.last_stream_id_received
+                }
+
+                fn set_last_stream_id_received(&mut self, stream_id: i32) {
+                    self.last_stream_id_received = Some(stream_id);
+                }
+            }
+        )*
+    };
+}
+
+impl_stanza! {
+    HeartbeatPing => HeartbeatPing,
+    HeartbeatAck => HeartbeatAck,
+    LoginResponse => LoginResponse,
+    IqStanza => IqStanza,
+    DataMessageStanza => DataMessageStanza,
+}
+
+/// IQ extension carrying a [`crate::mcs::SelectiveAck`]
+const SELECTIVE_ACK_EXTENSION: i32 = 12;
+/// IQ extension carrying a [`crate::mcs::StreamAck`]
+const STREAM_ACK_EXTENSION: i32 = 13;
+
+fn new_ack_iq(extension_id: i32, data: Vec<u8>) -> crate::mcs::IqStanza {
+    crate::mcs::IqStanza {
+        r#type: crate::mcs::iq_stanza::IqType::Set as i32,
+        id: String::new(),
+        extension: Some(crate::mcs::Extension {
+            id: extension_id,
+            data,
+        }),
+        ..Default::default()
+    }
+}
+
+/// Encode a HeartbeatAck reporting the last stanza received
+///
+/// [`MessageStream`] answers pings by itself, this is only needed when driving
+/// the connection by hand, see [`MessageStream::incoming_stream_id`].
+pub fn new_heartbeat_ack(last_stream_id_received: i32) -> BytesMut {
+    let ack = crate::mcs::HeartbeatAck {
+        last_stream_id_received: Some(last_stream_id_received),
+        ..Default::default()
+    };
+    let mut bytes = BytesMut::new();
+    encode_stanza(MessageTag::HeartbeatAck, &ack, &mut bytes);
 
     bytes
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio_stream::StreamExt;
+
+    const RECEIVED_SECS: u64 = 1_700_000_000;
+
+    /// Message received at [`RECEIVED_SECS`]
+    fn timed_message(ttl: Option<i32>, sent_ago: Option<i64>, queued: Option<i32>) -> DataMessage {
+        DataMessage {
+            ttl,
+            sent: sent_ago.map(|ago| RECEIVED_SECS as i64 - ago),
+            queued,
+            received_at: Some(UNIX_EPOCH + Duration::from_secs(RECEIVED_SECS)),
+            ..Default::default()
+        }
+    }
+
+    fn secs_since_received(secs: i64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(RECEIVED_SECS.checked_add_signed(secs).unwrap())
+    }
+
+    #[test]
+    fn queued_duration_prefers_the_server_count() {
+        let queued = |sent_ago, queued| timed_message(None, sent_ago, queued).queued_duration();
+        assert_eq!(queued(Some(500), Some(30)), Some(Duration::from_secs(30)));
+        assert_eq!(queued(None, Some(-5)), Some(Duration::ZERO));
+        // local clock against `sent` without a server count
+        assert_eq!(queued(Some(45), None), Some(Duration::from_secs(45)));
+        // sent "in the future" by a skewed clock
+        assert_eq!(queued(Some(-10), None), None);
+        assert_eq!(queued(None, None), None);
+    }
+
+    #[test]
+    fn expiry_counts_from_when_the_message_was_sent() {
+        // queued 30s of a 60s ttl, 30s left
+        let message = timed_message(Some(60), Some(500), Some(30));
+        assert_eq!(message.expires_at(), Some(secs_since_received(30)));
+        assert!(!message.is_expired());
+        assert!(message.expired_at(secs_since_received(30)));
+
+        let message = timed_message(Some(60), None, Some(90));
+        assert_eq!(message.expires_at(), Some(secs_since_received(-30)));
+        assert!(message.is_expired());
+
+        // no server count, `sent` decides
+        let message = timed_message(Some(60), Some(120), None);
+        assert_eq!(message.expires_at(), Some(secs_since_received(-60)));
+        assert!(message.is_expired());
+        assert_eq!(timed_message(Some(60), None, None).expires_at(), None);
+    }
+
+    #[test]
+    fn messages_without_positive_ttl_never_expire() {
+        for ttl in [None, Some(0), Some(-1)] {
+            let message = timed_message(ttl, Some(1_000_000), Some(1_000_000));
+            assert_eq!(message.expires_at(), None, "{ttl:?}");
+            assert!(!message.is_expired(), "{ttl:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_stream_error_ends_the_stream() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut stream = MessageStream::new(client);
+
+        // the `type` field announces 10 bytes, the frame holds only 2 of them
+        let mut frame = vec![MessageTag::StreamErrorStanza as u8, 4, 0x0a, 10, b's', b'h'];
+        let mut ping = BytesMut::new();
+        encode_stanza(MessageTag::HeartbeatPing, &crate::mcs::HeartbeatPing::default(), &mut ping);
+        frame.extend_from_slice(&ping);
+        server.write_all(&frame).await.unwrap();
+
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(Error::ProtobufDecode("MCS stream error", _)))
+        ));
+        assert!(matches!(stream.end_reason(), Some(EndReason::Failed)));
+        // the ping behind the stream error is not read anymore
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn sending_close_ends_the_sink() {
+        use futures_sink::Sink;
+        use tokio::io::AsyncReadExt;
+
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut stream = MessageStream::new(client);
+
+        Pin::new(&mut stream).start_send(OutgoingStanza::Close).unwrap();
+        assert!(matches!(stream.end_reason(), Some(EndReason::LocalClose)));
+        assert!(Pin::new(&mut stream)
+            .start_send(OutgoingStanza::HeartbeatPing)
+            .is_err());
+
+        // the Close queued before the end still goes out
+        std::future::poll_fn(|cx| Pin::new(&mut stream).poll_close(cx))
+            .await
+            .unwrap();
+        let mut sent = Vec::new();
+        server.read_to_end(&mut sent).await.unwrap();
+        assert_eq!(sent, [MessageTag::Close as u8, 0]);
+        assert!(stream.next().await.is_none());
+    }
+}
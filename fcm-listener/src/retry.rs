@@ -0,0 +1,193 @@
+use crate::Error;
+use std::future::Future;
+use std::time::Duration;
+
+/// When and how often checkin, Firebase Installations and register3 calls are repeated
+///
+/// The delay before retry `n` is `initial_delay * multiplier^(n - 1)`, capped
+/// at `max_delay`, then spread by up to `jitter` of itself in either direction
+/// so that many registrations started together do not retry in lockstep.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Total number of tries, including the first, `1` disables retries
+    pub max_attempts: u32,
+    /// Delay before the first retry
+    pub initial_delay: Duration,
+    /// Upper bound for a single delay before jitter
+    pub max_delay: Duration,
+    /// Factor the delay grows by after each retry
+    pub multiplier: f64,
+    /// Fraction of each delay that is randomized, between 0.0 and 1.0
+    pub jitter: f64,
+    /// Which errors are worth retrying, [`Error::is_retryable`] by default
+    pub retryable: fn(&Error) -> bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 6,
+            // PHONE_REGISTRATION_ERROR usually clears within a few seconds
+            initial_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            retryable: Error::is_retryable,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that gives up after the first failure
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Delay before retry number `retry`, counting from 1
+    ///
+    /// Out of range settings never panic: a non-finite `jitter` counts as none
+    /// and a delay too large for a [`Duration`] becomes `max_delay`.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let factor = self.multiplier.max(1.0).powi(exponent).min(1e6);
+        let base = Duration::try_from_secs_f64(self.initial_delay.as_secs_f64() * factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+
+        let jitter = if self.jitter.is_finite() {
+            self.jitter.clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        if jitter == 0.0 {
+            return base;
+        }
+        let spread = (rand::random::<f64>() * 2.0 - 1.0) * jitter;
+        Duration::try_from_secs_f64(base.as_secs_f64() * (1.0 + spread)).unwrap_or(self.max_delay)
+    }
+
+    /// Run `operation` until it succeeds, fails with a non-retryable error or
+    /// runs out of attempts
+    pub(crate) async fn run<T, F, Fut>(&self, api_name: &str, mut operation: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Err(e) if attempt < self.max_attempts && (self.retryable)(&e) => {
+                    let delay = self.delay(attempt);
+                    tracing::debug!(
+                        "{} attempt {}/{} failed: {}, retrying in {:?}",
+                        api_name,
+                        attempt,
+                        self.max_attempts,
+                        e,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => {
+                    if result.is_ok() && attempt > 1 {
+                        tracing::info!("{} succeeded on attempt {}", api_name, attempt);
+                    }
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn delay_backs_off_up_to_max_delay() {
+        let policy = policy();
+        let delays: Vec<_> = (1..=6).map(|retry| policy.delay(retry)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+        );
+        // retry 0 is treated like the first one
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let half = RetryPolicy {
+            jitter: 0.5,
+            ..policy()
+        };
+        for _ in 0..1000 {
+            let delay = half.delay(2);
+            assert!(delay >= Duration::from_millis(100), "{delay:?}");
+            assert!(delay <= Duration::from_millis(300), "{delay:?}");
+        }
+
+        // jitter above 1.0 is clamped, so the delay never goes negative
+        let excessive = RetryPolicy {
+            jitter: 5.0,
+            ..policy()
+        };
+        for _ in 0..1000 {
+            assert!(excessive.delay(1) <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn out_of_range_settings_do_not_panic() {
+        let nan_jitter = RetryPolicy {
+            jitter: f64::NAN,
+            ..policy()
+        };
+        assert_eq!(nan_jitter.delay(3), Duration::from_millis(400));
+
+        let infinite_jitter = RetryPolicy {
+            jitter: f64::INFINITY,
+            ..policy()
+        };
+        assert_eq!(infinite_jitter.delay(3), Duration::from_millis(400));
+
+        // a NaN multiplier counts as no growth
+        let nan_multiplier = RetryPolicy {
+            multiplier: f64::NAN,
+            ..policy()
+        };
+        assert_eq!(nan_multiplier.delay(5), Duration::from_millis(100));
+
+        let huge = RetryPolicy {
+            initial_delay: Duration::MAX,
+            max_delay: Duration::MAX,
+            multiplier: f64::MAX,
+            jitter: 1.0,
+            ..policy()
+        };
+        for retry in [1, 2, 1000, u32::MAX] {
+            // too large for a Duration once multiplied or jittered
+            huge.delay(retry);
+        }
+        let clamped = RetryPolicy {
+            jitter: 0.0,
+            ..huge
+        };
+        assert_eq!(clamped.delay(1000), Duration::MAX);
+    }
+}
@@ -0,0 +1,217 @@
+use crate::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Persistent IDs of received messages that the server has not confirmed yet
+///
+/// The IDs are sent in the next `LoginRequest` so the server does not
+/// redeliver those messages, and a message whose ID is already recorded is
+/// dropped instead of handed out again. Keeping them somewhere durable makes
+/// that hold across restarts, see [`FileIdStore`].
+///
+/// `insert` and `remove` are called from inside
+/// [`MessageStream::poll_next`](crate::MessageStream), so they must not block.
+/// Stores backed by slow storage buffer the changes and write them in `flush`,
+/// which [`FcmClient`](crate::FcmClient) calls on a blocking thread.
+pub trait PersistentIdStore: Send + Sync + std::fmt::Debug {
+    /// All recorded IDs, in the order they were received
+    fn load(&self) -> Result<Vec<String>, Error>;
+
+    /// Record a received ID, `false` if it was already recorded
+    fn insert(&self, id: &str) -> Result<bool, Error>;
+
+    /// Forget IDs the server has confirmed
+    fn remove(&self, ids: &[String]) -> Result<(), Error>;
+
+    /// Write buffered changes to durable storage, may block
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Store that keeps IDs for the lifetime of the process
+#[derive(Debug, Default)]
+pub struct MemoryIdStore {
+    ids: Mutex<Vec<String>>,
+}
+
+impl MemoryIdStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PersistentIdStore for MemoryIdStore {
+    fn load(&self) -> Result<Vec<String>, Error> {
+        Ok(self.ids.lock().expect("lock poisoned").clone())
+    }
+
+    fn insert(&self, id: &str) -> Result<bool, Error> {
+        let mut ids = self.ids.lock().expect("lock poisoned");
+        if ids.iter().any(|recorded| recorded == id) {
+            return Ok(false);
+        }
+        ids.push(id.to_string());
+        Ok(true)
+    }
+
+    fn remove(&self, confirmed: &[String]) -> Result<(), Error> {
+        self.ids
+            .lock()
+            .expect("lock poisoned")
+            .retain(|id| !confirmed.contains(id));
+        Ok(())
+    }
+}
+
+/// Store that keeps IDs in a file, one per line
+///
+/// Changes are kept in memory until [`PersistentIdStore::flush`], which
+/// rewrites the file through a temporary file next to it, so a crash leaves
+/// either the old or the new list behind. Dropping the store flushes it too.
+///
+/// The stream reports a received ID to the server with the next stanza it
+/// sends, which can go out before the flush. A crash in between loses the
+/// message: the server counts it as delivered but it was never handed out.
+/// [`FcmClient`](crate::FcmClient) flushes before handing out each message,
+/// which keeps that window to the time one write takes.
+#[derive(Debug)]
+pub struct FileIdStore {
+    path: PathBuf,
+    ids: Mutex<FileIds>,
+    /// Held while writing, so snapshots reach the file in the order they were taken
+    write_lock: Mutex<()>,
+}
+
+#[derive(Debug)]
+struct FileIds {
+    ids: Vec<String>,
+    /// Whether `ids` changed since the file was last written
+    dirty: bool,
+}
+
+impl FileIdStore {
+    /// Open the store at `path`, starting empty if the file does not exist
+    ///
+    /// The parent directory must exist.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let ids = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .lines()
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(String::from)
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(Error::Store(e)),
+        };
+        Ok(Self {
+            path,
+            ids: Mutex::new(FileIds { ids, dirty: false }),
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write(&self, ids: &[String]) -> Result<(), Error> {
+        let mut contents = String::new();
+        for id in ids {
+            contents.push_str(id);
+            contents.push('\n');
+        }
+
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        std::fs::write(&temp_path, contents).map_err(Error::Store)?;
+        std::fs::rename(&temp_path, &self.path).map_err(Error::Store)
+    }
+}
+
+impl PersistentIdStore for FileIdStore {
+    fn load(&self) -> Result<Vec<String>, Error> {
+        Ok(self.ids.lock().expect("lock poisoned").ids.clone())
+    }
+
+    fn insert(&self, id: &str) -> Result<bool, Error> {
+        let mut state = self.ids.lock().expect("lock poisoned");
+        if state.ids.iter().any(|recorded| recorded == id) {
+            return Ok(false);
+        }
+        state.ids.push(id.to_string());
+        state.dirty = true;
+        Ok(true)
+    }
+
+    fn remove(&self, confirmed: &[String]) -> Result<(), Error> {
+        let mut state = self.ids.lock().expect("lock poisoned");
+        let before = state.ids.len();
+        state.ids.retain(|id| !confirmed.contains(id));
+        if state.ids.len() != before {
+            state.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        let _writing = self.write_lock.lock().expect("lock poisoned");
+        let ids = {
+            let mut state = self.ids.lock().expect("lock poisoned");
+            if !state.dirty {
+                return Ok(());
+            }
+            state.dirty = false;
+            state.ids.clone()
+        };
+
+        let written = self.write(&ids);
+        if written.is_err() {
+            self.ids.lock().expect("lock poisoned").dirty = true;
+        }
+        written
+    }
+}
+
+impl Drop for FileIdStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            tracing::warn!("Failed to write persistent IDs to {:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_round_trip() {
+        let dir = std::env::temp_dir().join(format!("fcm-id-store-{:x}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("ids");
+
+        let store = FileIdStore::open(&path).unwrap();
+        assert!(store.load().unwrap().is_empty());
+        assert!(store.insert("0:1").unwrap());
+        assert!(store.insert("0:2").unwrap());
+        assert!(!store.insert("0:1").unwrap());
+        // nothing is written before the flush
+        assert!(!path.exists());
+
+        store.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "0:1\n0:2\n");
+        assert!(!dir.join("ids.tmp").exists());
+
+        store.remove(&["0:1".to_string()]).unwrap();
+        drop(store);
+        let store = FileIdStore::open(&path).unwrap();
+        assert_eq!(store.load().unwrap(), ["0:2"]);
+        assert!(!store.insert("0:2").unwrap());
+
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1079 @@
+//! Local stand-ins for Google's servers, for testing code built on this crate
+//!
+//! [`MockMcsServer`] speaks MCS over TLS on a loopback port. It answers the
+//! `LoginRequest` of [`GcmSession::connect`](crate::GcmSession::connect) and
+//! hands each connection to the test as a [`MockConnection`], which scripts
+//! what the client sees next: pings, data messages, IQs, stream errors, raw
+//! bytes split at arbitrary points, or the socket going away.
+//!
+//! [`MockHttpServer`] serves checkin, register3 and Firebase Installations over
+//! HTTPS. It checks requests the way the real services would, and the way this
+//! crate is meant to send them, answers them with new credentials unless a
+//! test queued a [`MockResponse`], and records every request it gets.
+//!
+//! ```rust,no_run
+//! use fcm_listener::test_support::{MockHttpServer, MockMcsServer, MockResponse};
+//! use fcm_listener::{FcmCredentials, Registration};
+//! # async fn example(creds: FcmCredentials) -> anyhow::Result<()> {
+//! let http_server = MockHttpServer::start().await?;
+//! let mcs_server = MockMcsServer::start().await?;
+//! let mut config = http_server.client_config();
+//! mcs_server.configure(&mut config)?;
+//!
+//! http_server.reply_to_register(MockResponse::register_error("PHONE_REGISTRATION_ERROR"));
+//! let registration = Registration::register(&config.http_client()?, &config, &creds).await?;
+//!
+//! let client = tokio::spawn(async move { registration.connect(&config, vec![]).await });
+//! let mut connection = mcs_server.accept().await?;
+//! let stream = client.await??;
+//!
+//! connection.send_heartbeat_ping().await?;
+//! connection.abort();
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::checkin::{AndroidCheckinRequest, AndroidCheckinResponse, GservicesSetting};
+use crate::mcs::{
+    Close, DataMessageStanza, HeartbeatAck, HeartbeatPing, IqStanza, LoginRequest, LoginResponse,
+    StreamErrorStanza,
+};
+use crate::{encode_stanza, ClientConfig, MessageTag, MtalkEndpoint};
+use bytes::{Bytes, BytesMut};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// MCS protocol version sent before the first server stanza
+const MCS_VERSION: u8 = 41;
+
+/// Pause between the writes of [`MockConnection::send_chunked`]
+const CHUNK_DELAY: Duration = Duration::from_millis(10);
+
+/// Host the mock servers listen on, also the name in their certificate
+const MOCK_HOST: &str = "127.0.0.1";
+
+/// Self-signed certificate and TLS acceptor for the loopback servers
+struct MockTls {
+    certificate: CertificateDer<'static>,
+    acceptor: TlsAcceptor,
+}
+
+impl MockTls {
+    fn generate() -> io::Result<Self> {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+
+        let generated = rcgen::generate_simple_self_signed(vec![MOCK_HOST.to_string()])
+            .map_err(io::Error::other)?;
+        let certificate = generated.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der());
+
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate.clone()], PrivateKeyDer::Pkcs8(key))
+            .map_err(io::Error::other)?;
+
+        Ok(Self {
+            certificate,
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+}
+
+/// MCS server on a loopback port
+///
+/// Logins are answered with [`MockMcsServer::login_response`], a successful one
+/// unless changed with [`MockMcsServer::with_login_response`].
+pub struct MockMcsServer {
+    listener: TcpListener,
+    tls: MockTls,
+    login_response: LoginResponse,
+}
+
+impl MockMcsServer {
+    /// Listen on a free port with a new self-signed certificate
+    pub async fn start() -> io::Result<Self> {
+        let tls = MockTls::generate()?;
+        let listener = TcpListener::bind((MOCK_HOST, 0)).await?;
+        Ok(Self {
+            listener,
+            tls,
+            login_response: LoginResponse {
+                id: "mock".into(),
+                stream_id: Some(1),
+                last_stream_id_received: Some(1),
+                ..Default::default()
+            },
+        })
+    }
+
+    /// Answer logins with `response`, e.g. one carrying an `error` to reject them
+    pub fn with_login_response(mut self, response: LoginResponse) -> Self {
+        self.login_response = response;
+        self
+    }
+
+    pub fn login_response(&self) -> &LoginResponse {
+        &self.login_response
+    }
+
+    pub fn address(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The server's certificate, trust it to connect
+    pub fn certificate(&self) -> &CertificateDer<'static> {
+        &self.tls.certificate
+    }
+
+    /// The server as an MCS endpoint
+    pub fn endpoint(&self) -> io::Result<MtalkEndpoint> {
+        Ok(MtalkEndpoint::new(MOCK_HOST, self.address()?.port()))
+    }
+
+    /// Point `config` at this server and trust its certificate
+    ///
+    /// Fallback servers are cleared so a failed connect is not retried elsewhere.
+    pub fn configure(&self, config: &mut ClientConfig) -> io::Result<()> {
+        let endpoint = self.endpoint()?;
+        config.endpoints.mtalk_host = endpoint.host;
+        config.endpoints.mtalk_port = endpoint.port;
+        config.endpoints.mtalk_fallbacks.clear();
+        config.extra_root_certificates.push(self.certificate().clone());
+        Ok(())
+    }
+
+    /// Default configuration pointed at this server, see [`MockMcsServer::configure`]
+    pub fn client_config(&self) -> ClientConfig {
+        let mut config = ClientConfig::default();
+        self.configure(&mut config)
+            .expect("listener has a local address");
+        config
+    }
+
+    /// Accept a client and answer its login
+    pub async fn accept(&self) -> io::Result<MockConnection> {
+        let mut connection = self.accept_without_response().await?;
+        let mut response = self.login_response.clone();
+        if response.server_timestamp.is_none() {
+            response.server_timestamp = Some(unix_millis());
+        }
+        connection.send_login_response(&response).await?;
+        Ok(connection)
+    }
+
+    /// Accept a client and read its login, leaving the answer to the test
+    ///
+    /// Follow up with [`MockConnection::send_login_response`], or with any other
+    /// stanza to see how the client copes with it in place of the response.
+    pub async fn accept_without_response(&self) -> io::Result<MockConnection> {
+        let (stream, _) = self.listener.accept().await?;
+        let mut stream = self.tls.acceptor.accept(stream).await?;
+
+        let version = stream.read_u8().await?;
+        if version != MCS_VERSION {
+            tracing::warn!("Mock MCS client sent protocol version {}", version);
+        }
+        let (tag, payload) = crate::push::read_frame(&mut stream).await?;
+        if tag != MessageTag::LoginRequest as u8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected a LoginRequest, got tag {tag}"),
+            ));
+        }
+        let login = decode(&payload)?;
+
+        Ok(MockConnection {
+            stream,
+            login,
+            version_sent: false,
+            stream_id_in: 1,
+            stream_id_out: 0,
+        })
+    }
+}
+
+/// Server side of one MCS connection
+///
+/// Stanzas are written and flushed one at a time. The ones that carry
+/// `last_stream_id_received` get the number of stanzas received from the
+/// client so far unless they already set it, acknowledging everything the
+/// client sent the way the real server does.
+pub struct MockConnection {
+    stream: TlsStream<TcpStream>,
+    /// The client's login
+    pub login: LoginRequest,
+    version_sent: bool,
+    stream_id_in: i32,
+    stream_id_out: i32,
+}
+
+impl MockConnection {
+    /// Number of stanzas received from the client, including the login
+    pub fn incoming_stream_id(&self) -> i32 {
+        self.stream_id_in
+    }
+
+    /// Number of stanzas sent to the client
+    pub fn outgoing_stream_id(&self) -> i32 {
+        self.stream_id_out
+    }
+
+    pub async fn send_login_response(&mut self, response: &LoginResponse) -> io::Result<()> {
+        self.send(MessageTag::LoginResponse, response).await
+    }
+
+    pub async fn send_heartbeat_ping(&mut self) -> io::Result<()> {
+        let ping = HeartbeatPing {
+            last_stream_id_received: Some(self.stream_id_in),
+            ..Default::default()
+        };
+        self.send(MessageTag::HeartbeatPing, &ping).await
+    }
+
+    pub async fn send_heartbeat_ack(&mut self) -> io::Result<()> {
+        let ack = HeartbeatAck {
+            last_stream_id_received: Some(self.stream_id_in),
+            ..Default::default()
+        };
+        self.send(MessageTag::HeartbeatAck, &ack).await
+    }
+
+    pub async fn send_data_message(&mut self, message: &DataMessageStanza) -> io::Result<()> {
+        let mut message = message.clone();
+        message.last_stream_id_received.get_or_insert(self.stream_id_in);
+        self.send(MessageTag::DataMessageStanza, &message).await
+    }
+
+    pub async fn send_iq(&mut self, iq: &IqStanza) -> io::Result<()> {
+        let mut iq = iq.clone();
+        iq.last_stream_id_received.get_or_insert(self.stream_id_in);
+        self.send(MessageTag::IqStanza, &iq).await
+    }
+
+    pub async fn send_stream_error(&mut self, r#type: &str, text: Option<&str>) -> io::Result<()> {
+        let error = StreamErrorStanza {
+            r#type: r#type.into(),
+            text: text.map(String::from),
+        };
+        self.send(MessageTag::StreamErrorStanza, &error).await
+    }
+
+    pub async fn send_close(&mut self) -> io::Result<()> {
+        self.send(MessageTag::Close, &Close {}).await
+    }
+
+    /// Send any stanza as is
+    pub async fn send<M: prost::Message>(&mut self, tag: MessageTag, message: &M) -> io::Result<()> {
+        let mut frame = BytesMut::new();
+        encode_stanza(tag, message, &mut frame);
+        self.send_raw(&frame).await?;
+        self.stream_id_out += 1;
+        Ok(())
+    }
+
+    /// Write bytes as they are, e.g. frames built with [`encode_stanza`]
+    ///
+    /// The protocol version byte goes out first if nothing was sent yet.
+    pub async fn send_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.send_version().await?;
+        self.stream.write_all(bytes).await?;
+        self.stream.flush().await
+    }
+
+    /// Write bytes in pieces of at most `chunk_len`, pausing briefly after each
+    ///
+    /// The pauses let the client see every piece in a read of its own, to test
+    /// frames and length varints split across reads.
+    pub async fn send_chunked(&mut self, bytes: &[u8], chunk_len: usize) -> io::Result<()> {
+        for chunk in bytes.chunks(chunk_len.max(1)) {
+            self.send_raw(chunk).await?;
+            tokio::time::sleep(CHUNK_DELAY).await;
+        }
+        Ok(())
+    }
+
+    async fn send_version(&mut self) -> io::Result<()> {
+        if !self.version_sent {
+            self.stream.write_u8(MCS_VERSION).await?;
+            self.version_sent = true;
+        }
+        Ok(())
+    }
+
+    /// Read the next stanza from the client
+    pub async fn receive(&mut self) -> io::Result<(MessageTag, BytesMut)> {
+        let (tag, payload) = crate::push::read_frame(&mut self.stream).await?;
+        self.stream_id_in += 1;
+        let tag = MessageTag::try_from(tag).map_err(|tag| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("unknown MCS tag {tag}"))
+        })?;
+        Ok((tag, payload))
+    }
+
+    /// Read stanzas from the client until one with `tag` arrives, and decode it
+    ///
+    /// Stanzas with other tags, such as the stream acks the client sends on its
+    /// own, are skipped.
+    pub async fn receive_stanza<M: prost::Message + Default>(
+        &mut self,
+        tag: MessageTag,
+    ) -> io::Result<M> {
+        loop {
+            let (received, payload) = self.receive().await?;
+            if received == tag {
+                return decode(&payload);
+            }
+            tracing::trace!("Mock MCS server skipped a {:?}", received);
+        }
+    }
+
+    /// End the connection with a TLS close_notify
+    pub async fn shutdown(mut self) -> io::Result<()> {
+        self.stream.shutdown().await
+    }
+
+    /// Drop the connection without closing TLS, the client sees an unexpected EOF
+    pub fn abort(self) {
+        drop(self.stream);
+    }
+}
+
+/// Path of the checkin endpoint on a [`MockHttpServer`]
+const CHECKIN_PATH: &str = "/checkin";
+
+/// Path of the register3 endpoint on a [`MockHttpServer`]
+const REGISTER_PATH: &str = "/c2dm/register3";
+
+/// Prefix of the Firebase Installations API on a [`MockHttpServer`]
+const INSTALLATIONS_PATH: &str = "/v1";
+
+/// register3 form of a registration under a Firebase Installation
+const REGISTER_FIELDS: [&str; 16] = [
+    "app",
+    "device",
+    "sender",
+    "cert",
+    "app_ver",
+    "target_ver",
+    "X-appid",
+    "X-Goog-Firebase-Installations-Auth",
+    "X-cliv",
+    "X-scope",
+    "X-subtype",
+    "X-gmp_app_id",
+    "X-Firebase-Client",
+    "X-app_ver_name",
+    "encryption_key",
+    "encryption_auth",
+];
+
+/// register3 form of a registration without a Firebase Installation
+const LEGACY_REGISTER_FIELDS: [&str; 8] = [
+    "app",
+    "device",
+    "sender",
+    "cert",
+    "app_ver",
+    "target_ver",
+    "encryption_key",
+    "encryption_auth",
+];
+
+/// register3 form of a topic subscription, unsubscribing appends `delete` and `X-delete`
+const TOPIC_FIELDS: [&str; 13] = [
+    "app",
+    "device",
+    "sender",
+    "cert",
+    "app_ver",
+    "X-appid",
+    "X-Goog-Firebase-Installations-Auth",
+    "X-cliv",
+    "X-gcm.topic",
+    "X-scope",
+    "X-subtype",
+    "X-gmp_app_id",
+    "X-app_ver_name",
+];
+
+/// register3 form of an unregistration, the installation fields are optional
+const UNREGISTER_FIELDS: [&str; 9] = [
+    "app",
+    "device",
+    "sender",
+    "cert",
+    "delete",
+    "X-scope",
+    "X-subtype",
+    "X-appid",
+    "X-Goog-Firebase-Installations-Auth",
+];
+
+/// Lifetime of the Firebase Installations auth tokens handed out
+const INSTALLATION_TOKEN_LIFETIME: &str = "604800s";
+
+/// Service a [`MockHttpServer`] request was made to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MockApi {
+    Checkin,
+    Register,
+    Installations,
+}
+
+/// Scripted answer to one request to a [`MockHttpServer`]
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl MockResponse {
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Successful checkin answered with `response`, gzipped like Google does
+    pub fn checkin(response: &AndroidCheckinResponse) -> Self {
+        use flate2::write::GzEncoder;
+        use prost::Message;
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder
+            .write_all(&response.encode_to_vec())
+            .expect("writing to a Vec cannot fail");
+        let body = encoder.finish().expect("writing to a Vec cannot fail");
+        Self::new(200, body)
+            .with_header("content-type", "application/x-protobuffer")
+            .with_header("content-encoding", "gzip")
+    }
+
+    /// register3 error such as `PHONE_REGISTRATION_ERROR`, sent with HTTP 200 as register3 does
+    pub fn register_error(reason: &str) -> Self {
+        Self::new(200, format!("Error={reason}"))
+    }
+
+    /// Firebase Installations error in Google's JSON error format
+    pub fn installations_error(status: u16, message: &str) -> Self {
+        let code = match status {
+            400 => "INVALID_ARGUMENT",
+            401 => "UNAUTHENTICATED",
+            403 => "PERMISSION_DENIED",
+            404 => "NOT_FOUND",
+            409 => "ALREADY_EXISTS",
+            429 => "RESOURCE_EXHAUSTED",
+            500 => "INTERNAL",
+            503 => "UNAVAILABLE",
+            _ => "UNKNOWN",
+        };
+        let body = serde_json::json!({
+            "error": {
+                "code": status,
+                "message": message,
+                "status": code,
+            },
+        });
+        Self::json(status, &body)
+    }
+
+    fn json(status: u16, body: &serde_json::Value) -> Self {
+        Self::new(status, body.to_string()).with_header("content-type", "application/json")
+    }
+
+    fn text(body: impl Into<String>) -> Self {
+        Self::new(200, body.into()).with_header("content-type", "text/plain")
+    }
+}
+
+/// A request as received by a [`MockHttpServer`]
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub api: MockApi,
+    pub method: String,
+    pub path: String,
+    /// Headers in the order received, with lowercase names
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+impl RecordedRequest {
+    /// First value of the header `name`, matched case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Fields of a form body in the order sent, decoded
+    pub fn form(&self) -> Vec<(String, String)> {
+        String::from_utf8_lossy(&self.body)
+            .split('&')
+            .filter(|field| !field.is_empty())
+            .map(|field| {
+                let (name, value) = field.split_once('=').unwrap_or((field, ""));
+                let decode = |part: &str| {
+                    urlencoding::decode(part)
+                        .map(|part| part.into_owned())
+                        .unwrap_or_else(|_| part.to_string())
+                };
+                (decode(name), decode(value))
+            })
+            .collect()
+    }
+
+    /// Value of the form field `name`
+    pub fn form_field(&self, name: &str) -> Option<String> {
+        self.form()
+            .into_iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value)
+    }
+
+    /// The body as JSON
+    pub fn json(&self) -> Option<serde_json::Value> {
+        serde_json::from_slice(&self.body).ok()
+    }
+
+    /// The body of a checkin request, decompressed and decoded
+    pub fn checkin_request(&self) -> io::Result<AndroidCheckinRequest> {
+        use std::io::Read;
+
+        let mut proto = Vec::new();
+        if self.header("content-encoding") == Some("gzip") {
+            flate2::read::GzDecoder::new(&self.body[..]).read_to_end(&mut proto)?;
+        } else {
+            proto.extend_from_slice(&self.body);
+        }
+        decode(&proto)
+    }
+}
+
+/// Checkin, register3 and Firebase Installations on a loopback HTTPS port
+///
+/// Requests are checked first, a malformed one is answered with the error the
+/// real service would give or, for register3 forms that deviate from what
+/// [`GcmSession::register`](crate::GcmSession::register) sends, with
+/// `Error=UNEXPECTED_FIELDS` and the fields received. Valid requests get the
+/// next response queued for their API, or else a successful answer with new
+/// credentials: a fresh android_id, FCM token or Firebase Installation.
+pub struct MockHttpServer {
+    address: SocketAddr,
+    certificate: CertificateDer<'static>,
+    state: Arc<Mutex<MockHttpState>>,
+    server: JoinHandle<()>,
+}
+
+impl MockHttpServer {
+    /// Listen on a free port with a new self-signed certificate
+    pub async fn start() -> io::Result<Self> {
+        let tls = MockTls::generate()?;
+        let listener = TcpListener::bind((MOCK_HOST, 0)).await?;
+        let address = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(MockHttpState::default()));
+        let server = tokio::spawn(serve_http(listener, tls.acceptor, state.clone()));
+
+        Ok(Self {
+            address,
+            certificate: tls.certificate,
+            state,
+            server,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The server's certificate, trust it to connect
+    pub fn certificate(&self) -> &CertificateDer<'static> {
+        &self.certificate
+    }
+
+    /// Point the checkin, register3 and Firebase Installations endpoints of
+    /// `config` at this server and trust its certificate
+    pub fn configure(&self, config: &mut ClientConfig) {
+        let base_url = format!("https://{}", self.address);
+        config.endpoints.checkin_url = format!("{base_url}{CHECKIN_PATH}");
+        config.endpoints.register_url = format!("{base_url}{REGISTER_PATH}");
+        config.endpoints.installations_url = format!("{base_url}{INSTALLATIONS_PATH}");
+        config.extra_root_certificates.push(self.certificate.clone());
+    }
+
+    /// Default configuration pointed at this server, see [`MockHttpServer::configure`]
+    ///
+    /// The pause after checkin is dropped to keep tests quick.
+    pub fn client_config(&self) -> ClientConfig {
+        let mut config = ClientConfig {
+            checkin_delay: Duration::ZERO,
+            ..Default::default()
+        };
+        self.configure(&mut config);
+        config
+    }
+
+    /// Answer the next valid checkin with `response`
+    pub fn reply_to_checkin(&self, response: MockResponse) {
+        self.queue(MockApi::Checkin, response);
+    }
+
+    /// Answer the next valid register3 request with `response`
+    pub fn reply_to_register(&self, response: MockResponse) {
+        self.queue(MockApi::Register, response);
+    }
+
+    /// Answer the next valid Firebase Installations request with `response`
+    pub fn reply_to_installations(&self, response: MockResponse) {
+        self.queue(MockApi::Installations, response);
+    }
+
+    fn queue(&self, api: MockApi, response: MockResponse) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.replies.entry(api).or_default().push_back(response);
+    }
+
+    /// Every request received so far, in order
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().expect("lock poisoned").requests.clone()
+    }
+
+    /// Requests received so far for one API
+    pub fn requests_to(&self, api: MockApi) -> Vec<RecordedRequest> {
+        let state = self.state.lock().expect("lock poisoned");
+        state
+            .requests
+            .iter()
+            .filter(|request| request.api == api)
+            .cloned()
+            .collect()
+    }
+}
+
+impl Drop for MockHttpServer {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// Accept HTTPS connections until aborted, which also ends the open ones
+async fn serve_http(listener: TcpListener, acceptor: TlsAcceptor, state: Arc<Mutex<MockHttpState>>) {
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+
+    let mut connections = JoinSet::new();
+    loop {
+        while connections.try_join_next().is_some() {}
+
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!("Mock HTTP server failed to accept: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let state = state.clone();
+        connections.spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::debug!("Mock HTTP TLS handshake failed: {}", e);
+                    return;
+                }
+            };
+            let service = service_fn(move |request| handle_http(state.clone(), request));
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Mock HTTP connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_http(
+    state: Arc<Mutex<MockHttpState>>,
+    request: hyper::Request<hyper::body::Incoming>,
+) -> Result<hyper::Response<http_body_util::Full<Bytes>>, hyper::Error> {
+    use http_body_util::BodyExt;
+
+    let (parts, body) = request.into_parts();
+    let body = body.collect().await?.to_bytes();
+    let path = parts.uri.path().to_string();
+
+    let api = if path == CHECKIN_PATH {
+        Some(MockApi::Checkin)
+    } else if path == REGISTER_PATH {
+        Some(MockApi::Register)
+    } else if path.starts_with(&format!("{INSTALLATIONS_PATH}/projects/")) {
+        Some(MockApi::Installations)
+    } else {
+        None
+    };
+
+    let response = match api {
+        Some(api) => {
+            let request = RecordedRequest {
+                api,
+                method: parts.method.to_string(),
+                path,
+                headers: parts
+                    .headers
+                    .iter()
+                    .map(|(name, value)| {
+                        let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                        (name.as_str().to_string(), value)
+                    })
+                    .collect(),
+                body,
+            };
+            state.lock().expect("lock poisoned").respond(request)
+        }
+        None => MockResponse::new(404, format!("no mock service at {path}")),
+    };
+
+    let mut builder = hyper::Response::builder().status(response.status);
+    for (name, value) in &response.headers {
+        builder = builder.header(name, value);
+    }
+    Ok(builder
+        .body(http_body_util::Full::new(Bytes::from(response.body)))
+        .expect("mock responses have valid status codes and headers"))
+}
+
+/// Firebase Installation handed out by a [`MockHttpServer`]
+struct MockInstallation {
+    refresh_token: String,
+    auth_token: String,
+}
+
+#[derive(Default)]
+struct MockHttpState {
+    replies: HashMap<MockApi, VecDeque<MockResponse>>,
+    requests: Vec<RecordedRequest>,
+    /// Installations by FID
+    installations: BTreeMap<String, MockInstallation>,
+}
+
+impl MockHttpState {
+    /// Record a request and work out its answer
+    fn respond(&mut self, request: RecordedRequest) -> MockResponse {
+        let checked = match request.api {
+            MockApi::Checkin => check_checkin(&request),
+            MockApi::Register => check_register(&request),
+            MockApi::Installations => self.check_installations(&request),
+        };
+        let response = match checked {
+            Err(rejection) => rejection,
+            Ok(()) => match self.replies.get_mut(&request.api).and_then(VecDeque::pop_front) {
+                Some(scripted) => scripted,
+                None => match request.api {
+                    MockApi::Checkin => self.checkin(&request),
+                    MockApi::Register => self.register(&request),
+                    MockApi::Installations => self.installations(&request),
+                },
+            },
+        };
+
+        tracing::debug!(
+            "Mock {:?} {} {} answered with HTTP {}",
+            request.api,
+            request.method,
+            request.path,
+            response.status
+        );
+        self.requests.push(request);
+        response
+    }
+
+    /// Assign a new android_id, or confirm the one of a re-checkin
+    fn checkin(&mut self, request: &RecordedRequest) -> MockResponse {
+        let checkin_request = request
+            .checkin_request()
+            .expect("checked by check_checkin");
+        let (android_id, security_token) = match (checkin_request.id, checkin_request.security_token) {
+            (Some(android_id), Some(security_token)) => (android_id as u64, security_token),
+            // android_id has to fit in the i64 of GcmSession
+            _ => (rand::random::<u64>() >> 1, rand::random::<u64>()),
+        };
+
+        MockResponse::checkin(&AndroidCheckinResponse {
+            stats_ok: true,
+            time_msec: Some(unix_millis()),
+            digest: Some(format!("mock-{:x}", rand::random::<u64>())),
+            settings_diff: Some(false),
+            setting: vec![GservicesSetting {
+                name: b"checkin_interval".to_vec(),
+                value: b"86400".to_vec(),
+            }],
+            android_id: Some(android_id),
+            security_token: Some(security_token),
+            ..Default::default()
+        })
+    }
+
+    /// Issue a token, or confirm a topic change or unregistration
+    fn register(&mut self, request: &RecordedRequest) -> MockResponse {
+        let field = |name: &str| request.form_field(name).unwrap_or_default();
+
+        if let Some(fid) = request.form_field("X-appid") {
+            let auth_token = field("X-Goog-Firebase-Installations-Auth");
+            match self.installations.get(&fid) {
+                Some(installation) if installation.auth_token != auth_token => {
+                    return MockResponse::register_error("AUTHENTICATION_FAILED");
+                }
+                _ => {}
+            }
+        }
+
+        if request.form_field("delete").is_some() {
+            return MockResponse::text(format!("deleted={}", field("app")));
+        }
+        if request.form_field("X-gcm.topic").is_some() {
+            return MockResponse::text(format!("token={}", field("sender")));
+        }
+
+        let prefix = request
+            .form_field("X-appid")
+            .unwrap_or_else(|| format!("mock{:x}", rand::random::<u32>()));
+        MockResponse::text(format!("token={prefix}:APA91b{}", random_token()))
+    }
+
+    /// Reject requests for installations this server does not know or with
+    /// the wrong refresh token, like the real API
+    fn check_installations(&self, request: &RecordedRequest) -> Result<(), MockResponse> {
+        check_installations(request)?;
+
+        let Some(fid) = installation_fid(&request.path) else {
+            return Ok(());
+        };
+        let Some(installation) = self.installations.get(fid) else {
+            return Err(MockResponse::installations_error(
+                404,
+                "Requested entity was not found.",
+            ));
+        };
+        let expected = format!("FIS_v2 {}", installation.refresh_token);
+        if request.header("authorization") != Some(expected.as_str()) {
+            return Err(MockResponse::installations_error(
+                401,
+                "Request is missing required authentication credential.",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Create an installation, refresh its auth token or delete it
+    fn installations(&mut self, request: &RecordedRequest) -> MockResponse {
+        let auth_token = |token: &str| {
+            serde_json::json!({
+                "token": token,
+                "expiresIn": INSTALLATION_TOKEN_LIFETIME,
+            })
+        };
+
+        match installation_fid(&request.path) {
+            None => {
+                let body = request.json().expect("checked by check_installations");
+                let fid = body["fid"].as_str().unwrap_or_default().to_string();
+                let installation = MockInstallation {
+                    refresh_token: format!("mock-refresh-{}", random_token()),
+                    auth_token: format!("mock-auth-{}", random_token()),
+                };
+                let collection = request.path.trim_start_matches(INSTALLATIONS_PATH);
+                let response = serde_json::json!({
+                    "name": format!("{}/{fid}", collection.trim_start_matches('/')),
+                    "fid": fid,
+                    "refreshToken": installation.refresh_token,
+                    "authToken": auth_token(&installation.auth_token),
+                });
+                self.installations.insert(fid, installation);
+                MockResponse::json(200, &response)
+            }
+            Some(fid) if request.method == "DELETE" => {
+                self.installations.remove(fid);
+                MockResponse::json(200, &serde_json::json!({}))
+            }
+            Some(fid) => {
+                let installation = self
+                    .installations
+                    .get_mut(fid)
+                    .expect("checked by check_installations");
+                installation.auth_token = format!("mock-auth-{}", random_token());
+                MockResponse::json(200, &auth_token(&installation.auth_token))
+            }
+        }
+    }
+}
+
+/// Checkins must be gzipped `AndroidCheckinRequest`s, as GMS sends them
+fn check_checkin(request: &RecordedRequest) -> Result<(), MockResponse> {
+    let bad_request = |reason: String| Err(MockResponse::new(400, reason));
+
+    if request.method != "POST" {
+        return bad_request(format!("checkin expects POST, got {}", request.method));
+    }
+    if request.header("content-type") != Some("application/x-protobuffer") {
+        return bad_request(format!(
+            "checkin expects content-type application/x-protobuffer, got {:?}",
+            request.header("content-type")
+        ));
+    }
+    if request.header("content-encoding") != Some("gzip") {
+        return bad_request("checkin expects a gzipped body".into());
+    }
+
+    let checkin_request = match request.checkin_request() {
+        Ok(checkin_request) => checkin_request,
+        Err(e) => return bad_request(format!("checkin body is not an AndroidCheckinRequest: {e}")),
+    };
+    if checkin_request.id.is_some() != checkin_request.security_token.is_some() {
+        return bad_request("checkin sent only one of id and security_token".into());
+    }
+    Ok(())
+}
+
+/// register3 requests must authenticate as the device they name and send
+/// their form fields in the order [`GcmSession`](crate::GcmSession) uses
+fn check_register(request: &RecordedRequest) -> Result<(), MockResponse> {
+    if request.method != "POST" {
+        return Err(MockResponse::new(405, "register3 expects POST"));
+    }
+    if request.header("content-type") != Some("application/x-www-form-urlencoded") {
+        return Err(MockResponse::new(400, "register3 expects a form body"));
+    }
+
+    let form = request.form();
+    let fields: Vec<&str> = form.iter().map(|(name, _)| name.as_str()).collect();
+    let field = |name: &str| {
+        form.iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.as_str())
+    };
+
+    let device = field("device").unwrap_or_default();
+    let authenticated = request
+        .header("authorization")
+        .and_then(|auth| auth.strip_prefix("AidLogin "))
+        .and_then(|credentials| credentials.split_once(':'))
+        .is_some_and(|(android_id, _)| android_id == device);
+    if !authenticated {
+        return Err(MockResponse::new(401, "Error=AUTHENTICATION_FAILED"));
+    }
+    if request.header("app") != field("app") {
+        return Err(MockResponse::register_error("INVALID_PARAMETERS"));
+    }
+
+    let expected: &[&str] = if field("X-gcm.topic").is_some() {
+        &TOPIC_FIELDS
+    } else if field("delete").is_some() {
+        &UNREGISTER_FIELDS
+    } else if field("X-appid").is_some() {
+        &REGISTER_FIELDS
+    } else {
+        &LEGACY_REGISTER_FIELDS
+    };
+    let in_order = match field("X-gcm.topic") {
+        Some(_) if field("delete").is_some() => {
+            fields.strip_suffix(&["delete", "X-delete"]) == Some(expected)
+        }
+        Some(_) => fields == expected,
+        // the installation fields are optional when unregistering
+        None if field("delete").is_some() => {
+            fields == expected || fields == expected[..expected.len() - 2]
+        }
+        None => fields == expected,
+    };
+    if !in_order {
+        return Err(MockResponse::register_error(&format!(
+            "UNEXPECTED_FIELDS {}",
+            fields.join(",")
+        )));
+    }
+    Ok(())
+}
+
+/// Firebase Installations requests must carry the app's API key and package
+fn check_installations(request: &RecordedRequest) -> Result<(), MockResponse> {
+    if request.header("x-goog-api-key").is_none_or(str::is_empty) {
+        return Err(MockResponse::installations_error(
+            403,
+            "Method doesn't allow unregistered callers (callers without established identity).",
+        ));
+    }
+    if request.header("x-android-package").is_none_or(str::is_empty) {
+        return Err(MockResponse::installations_error(
+            400,
+            "Missing x-android-package header.",
+        ));
+    }
+
+    match installation_fid(&request.path) {
+        None if request.method == "POST" => {
+            let body = request.json().unwrap_or_default();
+            let valid = body["fid"].as_str().is_some_and(|fid| !fid.is_empty())
+                && body["appId"].as_str().is_some_and(|app_id| !app_id.is_empty())
+                && body["authVersion"] == "FIS_v2";
+            if !valid {
+                return Err(MockResponse::installations_error(
+                    400,
+                    "Request contains an invalid argument.",
+                ));
+            }
+            Ok(())
+        }
+        Some(_) if request.method == "DELETE" => Ok(()),
+        Some(_) if request.method == "POST" && request.path.ends_with("/authTokens:generate") => {
+            Ok(())
+        }
+        _ => Err(MockResponse::installations_error(
+            404,
+            "Requested entity was not found.",
+        )),
+    }
+}
+
+/// FID in the path of a request about an existing installation
+fn installation_fid(path: &str) -> Option<&str> {
+    let (_, rest) = path.split_once("/installations/")?;
+    let fid = rest.strip_suffix("/authTokens:generate").unwrap_or(rest);
+    (!fid.is_empty() && !fid.contains('/')).then_some(fid)
+}
+
+fn random_token() -> String {
+    use base64::Engine;
+
+    let bytes: [u8; 24] = rand::random();
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn decode<M: prost::Message + Default>(payload: &[u8]) -> io::Result<M> {
+    M::decode(payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as i64)
+        .unwrap_or_default()
+}
@@ -0,0 +1,279 @@
+//! Checkin, register3 and Firebase Installations calls against [`MockHttpServer`]
+
+use fcm_listener::checkin::AndroidCheckinResponse;
+use fcm_listener::test_support::{MockApi, MockHttpServer, MockResponse, RecordedRequest};
+use fcm_listener::{ClientConfig, Error, FcmCredentials, GcmSession, RegisterError, Registration};
+
+fn credentials() -> FcmCredentials {
+    FcmCredentials {
+        sender_id: "123".into(),
+        api_key: "key".into(),
+        app_id: "1:123:android:abc".into(),
+        project_id: "project".into(),
+        package_name: "com.example".into(),
+        cert_sha1: Some("ab12".into()),
+        app_version: Some(7),
+        app_version_name: Some("1.0".into()),
+        target_sdk: Some(34),
+    }
+}
+
+fn client(server: &MockHttpServer) -> (reqwest::Client, ClientConfig) {
+    let config = server.client_config();
+    (config.http_client().unwrap(), config)
+}
+
+fn field_names(request: &RecordedRequest) -> Vec<String> {
+    request.form().into_iter().map(|(name, _)| name).collect()
+}
+
+#[tokio::test]
+async fn gzipped_checkin_round_trip() {
+    let server = MockHttpServer::start().await.unwrap();
+    let (http, config) = client(&server);
+
+    server.reply_to_checkin(MockResponse::checkin(&AndroidCheckinResponse {
+        stats_ok: true,
+        android_id: Some(4242),
+        security_token: Some(77),
+        digest: Some("mock-digest".into()),
+        ..Default::default()
+    }));
+    let session = GcmSession::checkin(&http, &config).await.unwrap();
+    assert_eq!(session.android_id, 4242);
+    assert_eq!(session.security_token, 77);
+    assert_eq!(session.digest.as_deref(), Some("mock-digest"));
+
+    let request = &server.requests_to(MockApi::Checkin)[0];
+    assert_eq!(request.header("content-encoding"), Some("gzip"));
+    let checkin = request.checkin_request().unwrap();
+    assert_eq!(checkin.id, None);
+    assert_eq!(checkin.security_token, None);
+
+    // a re-checkin identifies the device and sends back the digest
+    let refreshed = session.refresh(&http, &config).await.unwrap();
+    assert_eq!(refreshed.android_id, 4242);
+    let checkin = server.requests_to(MockApi::Checkin)[1]
+        .checkin_request()
+        .unwrap();
+    assert_eq!(checkin.id, Some(4242));
+    assert_eq!(checkin.security_token, Some(77));
+    assert_eq!(checkin.digest.as_deref(), Some("mock-digest"));
+}
+
+#[tokio::test]
+async fn register3_form_field_order() {
+    let server = MockHttpServer::start().await.unwrap();
+    let (http, config) = client(&server);
+
+    let mut registration = Registration::register(&http, &config, &credentials())
+        .await
+        .unwrap();
+    registration.subscribe_topic(&http, &config, "news").await.unwrap();
+    registration.unsubscribe_topic(&http, &config, "news").await.unwrap();
+    registration.unregister(&http, &config).await.unwrap();
+
+    let requests = server.requests_to(MockApi::Register);
+    assert_eq!(requests.len(), 4);
+    assert_eq!(
+        field_names(&requests[0]),
+        [
+            "app",
+            "device",
+            "sender",
+            "cert",
+            "app_ver",
+            "target_ver",
+            "X-appid",
+            "X-Goog-Firebase-Installations-Auth",
+            "X-cliv",
+            "X-scope",
+            "X-subtype",
+            "X-gmp_app_id",
+            "X-Firebase-Client",
+            "X-app_ver_name",
+            "encryption_key",
+            "encryption_auth",
+        ]
+    );
+    let topic_fields = [
+        "app",
+        "device",
+        "sender",
+        "cert",
+        "app_ver",
+        "X-appid",
+        "X-Goog-Firebase-Installations-Auth",
+        "X-cliv",
+        "X-gcm.topic",
+        "X-scope",
+        "X-subtype",
+        "X-gmp_app_id",
+        "X-app_ver_name",
+    ];
+    assert_eq!(field_names(&requests[1]), topic_fields);
+    assert_eq!(requests[1].form_field("X-gcm.topic").as_deref(), Some("/topics/news"));
+    let mut unsubscribe_fields = topic_fields.to_vec();
+    unsubscribe_fields.extend(["delete", "X-delete"]);
+    assert_eq!(field_names(&requests[2]), unsubscribe_fields);
+    assert_eq!(
+        field_names(&requests[3]),
+        [
+            "app",
+            "device",
+            "sender",
+            "cert",
+            "delete",
+            "X-scope",
+            "X-subtype",
+            "X-appid",
+            "X-Goog-Firebase-Installations-Auth",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn register3_rejects_fields_out_of_order() {
+    let server = MockHttpServer::start().await.unwrap();
+    let (http, config) = client(&server);
+
+    let text = http
+        .post(&config.endpoints.register_url)
+        .header("content-type", "application/x-www-form-urlencoded")
+        .header("authorization", "AidLogin 5:6")
+        .header("app", "a")
+        .body("device=5&app=a&sender=1&cert=&app_ver=1&target_ver=30&encryption_key=k&encryption_auth=a")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(
+        text,
+        "Error=UNEXPECTED_FIELDS device,app,sender,cert,app_ver,target_ver,encryption_key,encryption_auth"
+    );
+}
+
+#[tokio::test]
+async fn phone_registration_error_is_retried() {
+    let server = MockHttpServer::start().await.unwrap();
+    let (http, config) = client(&server);
+
+    server.reply_to_register(MockResponse::register_error("PHONE_REGISTRATION_ERROR"));
+    server.reply_to_register(MockResponse::register_error("PHONE_REGISTRATION_ERROR"));
+    let registration = Registration::register(&http, &config, &credentials())
+        .await
+        .unwrap();
+    assert_eq!(server.requests_to(MockApi::Register).len(), 3);
+    let fid = &registration.firebase_installation.as_ref().unwrap().fid;
+    assert!(registration.fcm_token().starts_with(fid.as_str()));
+
+    // errors that need another request are not retried
+    server.reply_to_register(MockResponse::register_error("INVALID_SENDER"));
+    let error = Registration::register(&http, &config, &credentials())
+        .await
+        .err()
+        .unwrap();
+    assert!(
+        matches!(error, Error::Register(_, RegisterError::InvalidSender)),
+        "{error}"
+    );
+    assert!(!error.is_retryable());
+    assert_eq!(server.requests_to(MockApi::Register).len(), 4);
+}
+
+#[tokio::test]
+async fn installations_4xx_is_rejected() {
+    let server = MockHttpServer::start().await.unwrap();
+    let (http, config) = client(&server);
+
+    server.reply_to_installations(MockResponse::installations_error(403, "API key not valid"));
+    let error = Registration::register(&http, &config, &credentials())
+        .await
+        .err()
+        .unwrap();
+    match &error {
+        Error::InstallationRejected(_, status, body) => {
+            assert_eq!(status.as_u16(), 403);
+            assert!(body.contains("API key not valid"), "{body}");
+        }
+        _ => panic!("expected an installation rejection, got {error}"),
+    }
+    assert!(!error.is_retryable());
+    assert!(server.requests_to(MockApi::Register).is_empty());
+
+    // auth tokens of a deleted installation cannot be refreshed
+    let mut registration = Registration::register(&http, &config, &credentials())
+        .await
+        .unwrap();
+    registration.unregister(&http, &config).await.unwrap();
+    let error = registration
+        .refresh_installation(&http, &config)
+        .await
+        .err()
+        .unwrap();
+    assert!(
+        matches!(error, Error::InstallationRejected(_, status, _) if status.as_u16() == 404),
+        "{error}"
+    );
+}
+
+#[tokio::test]
+async fn topic_subscription_refreshes_expired_auth_token() {
+    let server = MockHttpServer::start().await.unwrap();
+    let (http, config) = client(&server);
+
+    let mut registration = Registration::register(&http, &config, &credentials())
+        .await
+        .unwrap();
+    let installation = registration.firebase_installation.as_mut().unwrap();
+    let stale_token = installation.auth_token.clone();
+    installation.auth_token_expires_at = Some(1);
+
+    registration
+        .subscribe_topic(&http, &config, "news")
+        .await
+        .unwrap();
+    assert_eq!(registration.topics, ["news"]);
+
+    let installation = registration.firebase_installation.as_ref().unwrap();
+    assert_ne!(installation.auth_token, stale_token);
+    assert!(server
+        .requests_to(MockApi::Installations)
+        .iter()
+        .any(|request| request.path.ends_with("/authTokens:generate")));
+    let subscribe = server.requests_to(MockApi::Register).pop().unwrap();
+    assert_eq!(subscribe.form_field("X-gcm.topic").as_deref(), Some("/topics/news"));
+    assert_eq!(
+        subscribe.form_field("X-Goog-Firebase-Installations-Auth"),
+        Some(installation.auth_token.clone())
+    );
+}
+
+#[tokio::test]
+async fn unregister_refreshes_expired_auth_token() {
+    let server = MockHttpServer::start().await.unwrap();
+    let (http, config) = client(&server);
+
+    let mut registration = Registration::register(&http, &config, &credentials())
+        .await
+        .unwrap();
+    // the server no longer accepts a token past its expiry
+    let installation = registration.firebase_installation.as_mut().unwrap();
+    installation.auth_token = "mock-auth-expired".into();
+    installation.auth_token_expires_at = Some(1);
+
+    registration.unregister(&http, &config).await.unwrap();
+
+    let delete = server.requests_to(MockApi::Register).pop().unwrap();
+    assert_eq!(delete.form_field("delete").as_deref(), Some("true"));
+    let auth_token = delete
+        .form_field("X-Goog-Firebase-Installations-Auth")
+        .unwrap();
+    assert_ne!(auth_token, "mock-auth-expired");
+    assert_eq!(
+        auth_token,
+        registration.firebase_installation.unwrap().auth_token
+    );
+}
@@ -0,0 +1,239 @@
+//! MCS client behaviour against [`MockMcsServer`]
+
+use fcm_listener::mcs::{AppData, DataMessageStanza, ErrorInfo, HeartbeatAck, LoginResponse};
+use fcm_listener::test_support::{MockConnection, MockMcsServer};
+use fcm_listener::{
+    encode_stanza, ClientConfig, Error, Event, FcmClient, FcmCredentials, GcmSession, GcmToken,
+    HeartbeatOptions, ListenOptions, Message, MessageStream, MessageTag, Registration,
+};
+use std::time::Duration;
+use tokio_rustls::client::TlsStream;
+use tokio_stream::StreamExt;
+
+type Stream = MessageStream<TlsStream<tokio::net::TcpStream>>;
+
+fn session() -> GcmSession {
+    serde_json::from_value(serde_json::json!({"android_id": "4242", "security_token": "77"}))
+        .unwrap()
+}
+
+fn data_message(persistent_id: &str, value_len: usize) -> DataMessageStanza {
+    DataMessageStanza {
+        persistent_id: Some(persistent_id.into()),
+        from: "123".into(),
+        category: "com.example".into(),
+        app_data: vec![AppData {
+            key: "k".into(),
+            value: "x".repeat(value_len),
+        }],
+        ..Default::default()
+    }
+}
+
+/// Log in to `server` and wrap the connection in a message stream
+async fn connect(server: &MockMcsServer) -> (Stream, MockConnection) {
+    let config = server.client_config();
+    let client = tokio::spawn(async move { session().connect(&config, vec![]).await });
+    let connection = server.accept().await.unwrap();
+    let stream = MessageStream::from_connection(client.await.unwrap().unwrap(), HeartbeatOptions::default());
+    (stream, connection)
+}
+
+async fn next_persistent_id(stream: &mut Stream) -> Option<String> {
+    match stream.next().await {
+        Some(Ok(Message::Data(message))) => message.persistent_id,
+        other => panic!("expected a data message, got {:?}", other.map(|item| item.map(|_| ()))),
+    }
+}
+
+#[tokio::test]
+async fn length_varint_split_across_reads() {
+    let server = MockMcsServer::start().await.unwrap();
+    let (mut stream, mut connection) = connect(&server).await;
+
+    // over 127 bytes, so the length takes two varint bytes that arrive separately
+    let mut frame = bytes::BytesMut::new();
+    encode_stanza(MessageTag::DataMessageStanza, &data_message("p1", 300), &mut frame);
+    assert!(frame.len() > 130);
+    connection.send_chunked(&frame, 1).await.unwrap();
+
+    assert_eq!(next_persistent_id(&mut stream).await.as_deref(), Some("p1"));
+}
+
+#[tokio::test]
+async fn several_stanzas_in_one_write() {
+    let server = MockMcsServer::start().await.unwrap();
+    let (mut stream, mut connection) = connect(&server).await;
+
+    let mut frames = bytes::BytesMut::new();
+    encode_stanza(MessageTag::DataMessageStanza, &data_message("p1", 10), &mut frames);
+    encode_stanza(MessageTag::HeartbeatAck, &HeartbeatAck::default(), &mut frames);
+    encode_stanza(MessageTag::DataMessageStanza, &data_message("p2", 200), &mut frames);
+    encode_stanza(MessageTag::DataMessageStanza, &data_message("p3", 10), &mut frames);
+    connection.send_raw(&frames).await.unwrap();
+
+    for id in ["p1", "p2", "p3"] {
+        loop {
+            match stream.next().await {
+                Some(Ok(Message::Data(message))) => {
+                    assert_eq!(message.persistent_id.as_deref(), Some(id));
+                    break;
+                }
+                Some(Ok(_)) => continue,
+                other => panic!("stream ended early: {:?}", other.map(|item| item.err())),
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn login_rejection() {
+    let server = MockMcsServer::start().await.unwrap().with_login_response(LoginResponse {
+        id: "mock".into(),
+        error: Some(ErrorInfo {
+            code: 401,
+            message: Some("bad token".into()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    let config = server.client_config();
+    let client = tokio::spawn(async move { session().connect(&config, vec![]).await });
+    let connection = server.accept().await.unwrap();
+    assert_eq!(connection.login.user, "4242");
+
+    match client.await.unwrap() {
+        Err(Error::LoginRejected(e)) => {
+            assert_eq!(e.code, 401);
+            assert_eq!(e.message.as_deref(), Some("bad token"));
+        }
+        Err(e) => panic!("expected a login rejection, got {e}"),
+        Ok(_) => panic!("login succeeded"),
+    }
+}
+
+#[tokio::test]
+async fn heartbeat_ping_is_answered() {
+    let server = MockMcsServer::start().await.unwrap();
+    let (mut stream, mut connection) = connect(&server).await;
+
+    // the stream only writes while it is polled
+    let client = tokio::spawn(async move { while stream.next().await.is_some() {} });
+    connection.send_heartbeat_ping().await.unwrap();
+    let ack: HeartbeatAck = tokio::time::timeout(
+        Duration::from_secs(5),
+        connection.receive_stanza(MessageTag::HeartbeatAck),
+    )
+    .await
+    .expect("no heartbeat ack")
+    .unwrap();
+    // the login response and the ping
+    assert_eq!(ack.last_stream_id_received, Some(2));
+
+    connection.abort();
+    client.await.unwrap();
+}
+
+/// Next event that is not a connection attempt
+async fn next_event(listener: &mut fcm_listener::Listener) -> Event {
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(5), listener.next())
+            .await
+            .expect("no event from the listener")
+            .expect("listener ended");
+        if !matches!(event, Event::Connecting { .. }) {
+            return event;
+        }
+    }
+}
+
+#[tokio::test]
+async fn client_reconnects_after_stream_error_and_abort() {
+    let server = MockMcsServer::start().await.unwrap();
+    let registration = Registration {
+        gcm_session: session(),
+        gcm_token: GcmToken {
+            token: "mock-token".into(),
+        },
+        credentials: FcmCredentials {
+            sender_id: "123".into(),
+            api_key: "key".into(),
+            app_id: "1:123:android:abc".into(),
+            project_id: "project".into(),
+            package_name: "com.example".into(),
+            cert_sha1: None,
+            app_version: None,
+            app_version_name: None,
+            target_sdk: None,
+        },
+        firebase_installation: None,
+        topics: Vec::new(),
+    };
+    let config: ClientConfig = server.client_config();
+    let options = ListenOptions {
+        initial_backoff: Duration::from_millis(10),
+        ..ListenOptions::default()
+    };
+    let mut listener = FcmClient::new(config).listen(registration, options);
+
+    let mut connection = server.accept().await.unwrap();
+    assert!(matches!(next_event(&mut listener).await, Event::Connected { .. }));
+    connection.send_stream_error("shutdown", Some("maintenance")).await.unwrap();
+    match next_event(&mut listener).await {
+        Event::Disconnected {
+            error: Some(Error::StreamError { r#type, text }),
+            ..
+        } => {
+            assert_eq!(r#type, "shutdown");
+            assert_eq!(text.as_deref(), Some("maintenance"));
+        }
+        _ => panic!("expected the stream error"),
+    }
+
+    let mut connection = server.accept().await.unwrap();
+    assert!(matches!(next_event(&mut listener).await, Event::Connected { .. }));
+    connection.send_data_message(&data_message("p1", 10)).await.unwrap();
+    match next_event(&mut listener).await {
+        Event::Message {
+            message,
+            registration,
+            ..
+        } => {
+            assert_eq!(message.persistent_id.as_deref(), Some("p1"));
+            assert_eq!(registration.unwrap().fcm_token(), "mock-token");
+        }
+        _ => panic!("expected the data message"),
+    }
+    connection.abort();
+    assert!(matches!(
+        next_event(&mut listener).await,
+        Event::Disconnected { error: Some(_), .. }
+    ));
+
+    // the unconfirmed message is reported in the next login
+    let connection = server.accept().await.unwrap();
+    assert_eq!(connection.login.received_persistent_id, vec!["p1".to_string()]);
+    assert!(matches!(next_event(&mut listener).await, Event::Connected { .. }));
+    listener.close();
+}
+
+#[tokio::test]
+async fn unreachable_primary_falls_back_within_attempt_timeout() {
+    let server = MockMcsServer::start().await.unwrap();
+    // completes the TCP handshake from its backlog but never answers, like a filtered port
+    let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut config = server.client_config();
+    config.endpoints.mtalk_port = silent.local_addr().unwrap().port();
+    config.endpoints.mtalk_fallbacks = vec![server.endpoint().unwrap()];
+    config.mtalk_attempt_timeout = Duration::from_millis(300);
+
+    let started = tokio::time::Instant::now();
+    let client = tokio::spawn(async move { session().connect(&config, vec![]).await });
+    let _connection = server.accept().await.unwrap();
+    let connection = client.await.unwrap().unwrap();
+    let elapsed = started.elapsed();
+
+    assert_eq!(connection.endpoint, server.endpoint().unwrap());
+    assert!(elapsed >= Duration::from_millis(300), "{elapsed:?}");
+    assert!(elapsed < Duration::from_secs(5), "{elapsed:?}");
+}
@@ -61,8 +61,8 @@ pub fn patch_apk(config: PatchConfig) -> Result<()> {
     // Step 2: Extract Firebase credentials
     println!("\n[2/8] Extracting Firebase credentials...");
     let firebase_creds = extract::extract_firebase_credentials_from_decoded(&decoded_dir)?;
-    if firebase_creds.app_id.is_some() {
-        println!("  App ID: {}", firebase_creds.app_id.as_ref().unwrap());
+    if let Some(app_id) = &firebase_creds.app_id {
+        println!("  App ID: {}", app_id);
         println!("  Project: {}", firebase_creds.project_id.as_deref().unwrap_or("unknown"));
         println!("  API Key: {}...", &firebase_creds.api_key.as_deref().unwrap_or("none")[..20.min(firebase_creds.api_key.as_deref().unwrap_or("").len())]);
     } else {
@@ -295,7 +295,7 @@ fn patch_on_start_command_in_dir(d41_dir: &Path) -> Result<()> {
     for entry in fs::read_dir(d41_dir)? {
         let entry = entry?;
         let path = entry.path();
-        if path.extension().map_or(false, |e| e == "smali") {
+        if path.extension().is_some_and(|e| e == "smali") {
             let content = fs::read_to_string(&path)?;
             if content.contains("onStartCommand(Landroid/content/Intent;II)I") {
                 println!("  Found onStartCommand in: {:?}", path.file_name().unwrap());